@@ -0,0 +1,383 @@
+use crate::*;
+use std::fs;
+
+// Every binary starts with these bytes
+pub const MAGIC: [u8; 4] = *b"ALIC";
+// Bumped whenever the instruction encoding changes in a way old binaries can't run on
+pub const ISA_VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 10; // Magic, version, entry point and section count
+const SECTION_HEADER_SIZE: usize = 6; // Kind, address and length
+
+/// What a section holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Code,
+    Data,
+}
+
+impl SectionKind {
+    fn to_u16(self) -> u16 {
+        match self {
+            SectionKind::Code => 0,
+            SectionKind::Data => 1,
+        }
+    }
+
+    fn from_u16(kind: u16) -> Option<SectionKind> {
+        match kind {
+            0 => Some(SectionKind::Code),
+            1 => Some(SectionKind::Data),
+            _ => None,
+        }
+    }
+}
+
+/// A run of words loaded at a fixed address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub kind: SectionKind,
+    pub address: u16, // Word address the first word is loaded at
+    pub words: Vec<u16>,
+}
+
+/// A binary file: a header with the entry point, followed by its sections.
+/// Every number in the file is a little-endian u16:
+///
+/// ```text
+/// "ALIC" version entry section_count
+/// kind address length word*length    (once per section)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binary {
+    pub entry: u16, // Address the PC starts at
+    pub sections: Vec<Section>,
+}
+
+impl Binary {
+    /// Encodes an assembled program starting at address 0. Its instructions go into code
+    /// sections, and its WORDs, which hold the program's data, into data sections.
+    pub fn from_program(program: &[Instruction]) -> Binary {
+        let mut sections: Vec<Section> = Vec::new();
+        for instruction in program {
+            let kind = match instruction {
+                Instruction::WORD(_) => SectionKind::Data,
+                _ => SectionKind::Code,
+            };
+            let words = CPU::encode_instruction(instruction);
+            match sections.last_mut() {
+                Some(section) if section.kind == kind => section.words.extend(words),
+                last => {
+                    let address = last.map_or(0, |last| last.address + last.words.len() as u16);
+                    sections.push(Section {
+                        kind,
+                        address,
+                        words,
+                    });
+                }
+            }
+        }
+        Binary { entry: 0, sections }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        let mut push = |value: u16| bytes.extend(value.to_le_bytes());
+        push(ISA_VERSION);
+        push(self.entry);
+        push(self.sections.len() as u16);
+        for section in &self.sections {
+            push(section.kind.to_u16());
+            push(section.address);
+            push(section.words.len() as u16);
+            for &word in &section.words {
+                push(word);
+            }
+        }
+        bytes
+    }
+
+    /// Parses and validates a binary.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Binary, BinaryError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(BinaryError::NotABinary);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(BinaryError::Truncated);
+        }
+
+        let mut offset = MAGIC.len();
+        let mut next = || -> Result<u16, BinaryError> {
+            let word = bytes
+                .get(offset..offset + 2)
+                .ok_or(BinaryError::Truncated)?;
+            offset += 2;
+            Ok(u16::from_le_bytes([word[0], word[1]]))
+        };
+
+        let version = next()?;
+        if version != ISA_VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+        let entry = next()?;
+        let section_count = next()?;
+
+        let mut sections = Vec::new();
+        for _ in 0..section_count {
+            let kind = next()?;
+            let kind = SectionKind::from_u16(kind).ok_or(BinaryError::UnknownSection(kind))?;
+            let address = next()?;
+            let length = next()?;
+            let words = (0..length)
+                .map(|_| next())
+                .collect::<Result<Vec<u16>, _>>()?;
+            sections.push(Section {
+                kind,
+                address,
+                words,
+            });
+        }
+
+        let size = HEADER_SIZE
+            + sections
+                .iter()
+                .map(|section| SECTION_HEADER_SIZE + section.words.len() * 2)
+                .sum::<usize>();
+        if bytes.len() > size {
+            return Err(BinaryError::TrailingBytes(bytes.len() - size));
+        }
+
+        let binary = Binary { entry, sections };
+        binary.validate()?;
+        Ok(binary)
+    }
+
+    /// Checks that every section fits in memory without overlapping another,
+    /// and that the entry point is inside a code section.
+    pub fn validate(&self) -> Result<(), BinaryError> {
+        let mut used = vec![false; MEMORY_SIZE];
+        for section in &self.sections {
+            let start = section.address as usize;
+            let end = start + section.words.len();
+            if end > MEMORY_SIZE {
+                return Err(BinaryError::SectionOutOfMemory(
+                    section.address,
+                    section.words.len(),
+                ));
+            }
+            if let Some(overlap) = used[start..end].iter().position(|&used| used) {
+                return Err(BinaryError::OverlappingSections((start + overlap) as u16));
+            }
+            used[start..end].fill(true);
+        }
+
+        let entry_in_code = self.sections.iter().any(|section| {
+            section.kind == SectionKind::Code
+                && (section.address..section.address + section.words.len() as u16)
+                    .contains(&self.entry)
+        });
+        if !entry_in_code {
+            return Err(BinaryError::EntryOutOfRange(self.entry));
+        }
+        Ok(())
+    }
+
+    /// Reads a raw dump from before this format: big-endian words with no header, loaded at
+    /// address 0 and run from there.
+    pub fn from_legacy(bytes: &[u8]) -> Result<Binary, BinaryError> {
+        let words = bytes
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect();
+        let binary = Binary {
+            entry: 0,
+            sections: vec![Section {
+                kind: SectionKind::Code,
+                address: 0,
+                words,
+            }],
+        };
+        binary.validate()?;
+        Ok(binary)
+    }
+
+    /// Checks if a file without a header is a raw dump from before this format, rather than
+    /// assembly. Words nearly always have bytes that text doesn't, like 0 or invalid UTF-8.
+    pub fn is_legacy(bytes: &[u8]) -> bool {
+        let text_byte = |byte: &u8| !byte.is_ascii_control() || b"\t\n\r\x0c".contains(byte);
+        !bytes.is_empty()
+            && bytes.len().is_multiple_of(2)
+            && (std::str::from_utf8(bytes).is_err() || !bytes.iter().all(text_byte))
+    }
+
+    /// Validates the binary and writes it to a file in the given format.
+    pub fn write(&self, filename: &str, format: ImageFormat) -> Result<(), Error> {
+        self.validate()
+            .map_err(|e| Error::Binary(format!("Error writing binary file '{}'", filename), e))?;
+        let bytes = match format {
+            ImageFormat::Binary => self.to_bytes(),
+            ImageFormat::Ihex => to_ihex(self).into_bytes(),
+            ImageFormat::Srec => to_srec(self).into_bytes(),
+        };
+        fs::write(filename, bytes)
+            .map_err(|e| Error::Io(format!("Error writing binary file '{}'", filename), e))
+    }
+
+    /// Reads a binary in any of the image formats, picked by looking at the file.
+    pub fn read(filename: &str) -> Result<Binary, Error> {
+        let bytes = fs::read(filename)
+            .map_err(|e| Error::Io(format!("Error reading binary file '{}'", filename), e))?;
+        let text = || String::from_utf8_lossy(&bytes).into_owned();
+        let binary = match ImageFormat::sniff(&bytes) {
+            Some(ImageFormat::Binary) => Binary::from_bytes(&bytes),
+            Some(ImageFormat::Ihex) => from_ihex(&text()),
+            Some(ImageFormat::Srec) => from_srec(&text()),
+            None if Binary::is_legacy(&bytes) => Binary::from_legacy(&bytes),
+            None => Err(BinaryError::NotABinary),
+        };
+        binary.map_err(|e| Error::Binary(format!("Error loading binary file '{}'", filename), e))
+    }
+
+    /// Prints the header, then the address, raw words and assembly of every instruction.
+    /// Data sections are printed as `.word`s.
+    pub fn disassemble(&self) {
+        println!("; entry point: {}", self.entry);
+        for section in &self.sections {
+            let kind = match section.kind {
+                SectionKind::Code => "code",
+                SectionKind::Data => "data",
+            };
+            println!(
+                "; {} section at {}, {} words",
+                kind,
+                section.address,
+                section.words.len()
+            );
+            match section.kind {
+                SectionKind::Code => disassemble(&section.words, section.address as usize),
+                SectionKind::Data => {
+                    for (i, word) in section.words.iter().enumerate() {
+                        println!(
+                            "{:>3}: {:<10} {}",
+                            section.address as usize + i,
+                            format!("{:04x}", word),
+                            Instruction::WORD(*word)
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Binary {
+        Binary::from_program(&[
+            Instruction::MOV(0, 5),
+            Instruction::CALL(4),
+            Instruction::HALT,
+            Instruction::WORD(0),
+            Instruction::WORD(0x1234),
+        ])
+    }
+
+    #[test]
+    fn groups_words_into_code_and_data_sections() {
+        let binary = sample();
+        let kinds: Vec<_> = binary
+            .sections
+            .iter()
+            .map(|section| (section.kind, section.address, section.words.len()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [(SectionKind::Code, 0, 4), (SectionKind::Data, 4, 2)]
+        );
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let binary = sample();
+        let bytes = binary.to_bytes();
+        assert!(bytes.starts_with(&MAGIC));
+        assert_eq!(bytes[4..6], ISA_VERSION.to_le_bytes());
+        assert_eq!(Binary::from_bytes(&bytes), Ok(binary));
+    }
+
+    #[test]
+    fn keeps_zero_words() {
+        let binary = Binary::from_bytes(&sample().to_bytes()).unwrap();
+        assert_eq!(binary.sections[0].words[3], 0); // The HALT
+        assert_eq!(binary.sections[1].words, [0, 0x1234]);
+    }
+
+    #[test]
+    fn rejects_malformed_binaries() {
+        let bytes = sample().to_bytes();
+        assert_eq!(Binary::from_bytes(b"ALIX"), Err(BinaryError::NotABinary));
+        assert_eq!(Binary::from_bytes(&bytes[..6]), Err(BinaryError::Truncated));
+        assert_eq!(
+            Binary::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(BinaryError::Truncated)
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.extend([0, 0, 0]);
+        assert_eq!(
+            Binary::from_bytes(&trailing),
+            Err(BinaryError::TrailingBytes(3))
+        );
+
+        let mut version = bytes.clone();
+        version[4..6].copy_from_slice(&7u16.to_le_bytes());
+        assert_eq!(
+            Binary::from_bytes(&version),
+            Err(BinaryError::UnsupportedVersion(7))
+        );
+
+        let mut kind = bytes.clone();
+        kind[HEADER_SIZE..HEADER_SIZE + 2].copy_from_slice(&9u16.to_le_bytes());
+        assert_eq!(
+            Binary::from_bytes(&kind),
+            Err(BinaryError::UnknownSection(9))
+        );
+    }
+
+    #[test]
+    fn rejects_sections_that_dont_fit() {
+        let mut binary = sample();
+        binary.sections[1].address = 2;
+        assert_eq!(
+            Binary::from_bytes(&binary.to_bytes()),
+            Err(BinaryError::OverlappingSections(2))
+        );
+
+        binary.sections[1].address = MEMORY_SIZE as u16 - 1;
+        assert_eq!(
+            Binary::from_bytes(&binary.to_bytes()),
+            Err(BinaryError::SectionOutOfMemory(MEMORY_SIZE as u16 - 1, 2))
+        );
+
+        let mut binary = sample();
+        binary.entry = 4; // In the data section
+        assert_eq!(
+            Binary::from_bytes(&binary.to_bytes()),
+            Err(BinaryError::EntryOutOfRange(4))
+        );
+    }
+
+    #[test]
+    fn loads_headerless_dumps_as_big_endian() {
+        let bytes = [0x20, 0x05, 0xa0, 0x00, 0x00, 0x00]; // MOV ax, 5 / PRINT ax / HALT
+        assert!(Binary::is_legacy(&bytes));
+        let binary = Binary::from_legacy(&bytes).unwrap();
+        assert_eq!(binary.entry, 0);
+        assert_eq!(binary.sections[0].words, [0x2005, 0xa000, 0]);
+
+        assert!(!Binary::is_legacy(b"mov ax, 5\n"));
+        assert!(!Binary::is_legacy(&bytes[..5]));
+    }
+}
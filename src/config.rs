@@ -1,16 +1,38 @@
-use serde::Deserialize;
-
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    pub debug: bool,
-    pub verbose_debug: bool,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            debug: true,
-            verbose_debug: false,
-        }
-    }
-}
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// How arithmetic instructions handle results that don't fit in a register.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ArithmeticMode {
+    /// Wrap around modulo 2^16
+    Wrap,
+    /// Clamp to the smallest or largest unsigned value
+    Saturate,
+    /// Halt the CPU on unsigned overflow or a negative result
+    #[default]
+    Trap,
+    /// Two's-complement numbers, halting the CPU on signed overflow
+    Signed,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub debug: bool,
+    pub verbose_debug: bool,
+    #[serde(default)]
+    pub arithmetic: ArithmeticMode,
+    #[serde(default)]
+    pub include_paths: Vec<String>, // Directories `.include` looks in
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            debug: true,
+            verbose_debug: false,
+            arithmetic: ArithmeticMode::default(),
+            include_paths: Vec::new(),
+        }
+    }
+}
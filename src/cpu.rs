@@ -1,14 +1,48 @@
 use crate::*;
-use std::fs::File;
-use std::io::{self, Read, Write};
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, Write};
+
+// Status flag bits
+pub const FLAG_ZERO: u16 = 1 << 0;
+pub const FLAG_CARRY: u16 = 1 << 1;
+pub const FLAG_NEGATIVE: u16 = 1 << 2;
+pub const FLAG_OVERFLOW: u16 = 1 << 3;
+
+/// Encodes a jump, with the condition in the DEST field and the address in the low 8 bits.
+fn encode_jump(condition: u16, address: u16) -> u16 {
+    (JMP_OPCODE << 12) | (condition << 8) | (address & 0xFF)
+}
+
+/// Encodes the first word of an extended instruction, with its register in the DEST field.
+fn encode_extended(reg: u16, ext_opcode: u16) -> u16 {
+    (EXT_OPCODE << 12) | (reg << 8 & 0xF00) | (ext_opcode & 0xFF)
+}
+
+/// What happened when the CPU was stepped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepResult {
+    Executed,        // An instruction ran, and the CPU can keep going
+    Halted,          // The CPU reached a HALT or the end of memory
+    Fault(CpuError), // The instruction faulted, and the PC points at it
+    Breakpoint(u16), // An instruction ran, and the PC landed on a breakpoint
+    WaitingForInput, // The next instruction is an IN, but there is no input queued
+}
 
 // CPU struct
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub registers: [u16; 16],
     pub memory: [u16; MEMORY_SIZE],
-    pub pc: u16, // Change to u16
-    pub running: bool,
-    pub zflag: bool,
+    pub pc: u16,          // Change to u16
+    pub sp: u16,          // Stack pointer, the address of the value on top of the stack
+    pub stack_limit: u16, // The stack can't grow down to here, so it can't overwrite the program
+    pub running: bool,    // False once the CPU halts or faults
+    pub flags: u16,       // Status flags, see the FLAG_* bits
+    pub arithmetic: ArithmeticMode,
+    pub breakpoints: HashSet<u16>,
+    pub input: VecDeque<u16>,        // Values waiting to be read by IN
+    pub output: Option<Vec<String>>, // Lines from PRINT, collected here instead of printed when set
+    verbose_debug: bool, // Read from the config once, since it's checked for every instruction
 }
 
 impl CPU {
@@ -17,15 +51,45 @@ impl CPU {
             registers: [0; 16],
             memory: [0; MEMORY_SIZE],
             pc: 0,
-            running: false,
-            zflag: false,
+            sp: MEMORY_SIZE as u16, // The stack starts out empty
+            stack_limit: (MEMORY_SIZE - STACK_SIZE) as u16,
+            running: true,
+            flags: 0,
+            arithmetic: ArithmeticMode::default(),
+            breakpoints: HashSet::new(),
+            input: VecDeque::new(),
+            output: None,
+            verbose_debug: declare_config().verbose_debug,
         }
     }
 
+    /// Starts a program over on a fresh CPU. Breakpoints are kept, and so is collecting the
+    /// output of PRINT, since those belong to whatever is running the program.
+    pub fn reset(&mut self, program: &[Instruction], arithmetic: ArithmeticMode) {
+        let breakpoints = std::mem::take(&mut self.breakpoints);
+        let collect_output = self.output.is_some();
+        *self = CPU::new();
+        self.arithmetic = arithmetic;
+        self.breakpoints = breakpoints;
+        if collect_output {
+            self.output = Some(Vec::new());
+        }
+        self.load_program(program);
+    }
+
+    /// Moves the PC, which lets the CPU carry on from there even after a HALT or a fault.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+        self.running = true;
+    }
+
     pub fn load_program(&mut self, program: &[Instruction]) {
-        for (i, instruction) in program.iter().enumerate() {
-            if i < MEMORY_SIZE {
-                self.memory[i] = self.encode_instruction(instruction);
+        let mut address = 0; // Instructions can take up more than one word
+        for instruction in program {
+            let words = CPU::encode_instruction(instruction);
+            if address + words.len() <= MEMORY_SIZE {
+                self.memory[address..address + words.len()].copy_from_slice(&words);
+                address += words.len();
             } else {
                 eprintln!(
                     "{}",
@@ -34,62 +98,114 @@ impl CPU {
                 break;
             }
         }
-        let config = declare_config();
-        if config.verbose_debug {
+        self.stack_limit = self.stack_limit.max(address as u16);
+        if self.verbose_debug {
             println!("{:?}", self.memory);
         }
     }
 
-    pub fn encode_instruction(&self, instruction: &Instruction) -> u16 {
+    pub fn encode_instruction(instruction: &Instruction) -> Vec<u16> {
         match instruction {
             Instruction::ADD(dst, src) => {
-                (ADD_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+                vec![(ADD_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)]
             }
             Instruction::MOV(dst, value) => {
-                (MOV_OPCODE << 12) | ((*dst) << 8 & 0xF00) | (*value & 0xFF)
+                vec![(MOV_OPCODE << 12) | ((*dst) << 8 & 0xF00) | (*value & 0xFF)]
             }
             Instruction::MUL(dst, src) => {
-                (MUL_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+                vec![(MUL_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)]
             }
             Instruction::SUB(dst, src) => {
-                (SUB_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+                vec![(SUB_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)]
             }
             Instruction::SWAP(dst, src) => {
-                (SWAP_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+                vec![(SWAP_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)]
             }
             Instruction::DIV(dst, src) => {
-                (DIV_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+                vec![(DIV_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)]
             }
-            Instruction::CLR(src) => (CLR_OPCODE << 12) | ((*src) << 4 & 0x0F0),
-            Instruction::INC(src) => (INC_OPCODE << 12) | ((*src) << 4 & 0x0F0),
-            Instruction::DEC(src) => (DEC_OPCODE << 12) | ((*src) << 4 & 0x0F0),
-            Instruction::PRINT(src) => (PRINT_OPCODE << 12) | ((*src) << 4 & 0x0F0),
+            Instruction::CLR(src) => vec![(CLR_OPCODE << 12) | ((*src) << 4 & 0x0F0)],
+            Instruction::INC(src) => vec![(INC_OPCODE << 12) | ((*src) << 4 & 0x0F0)],
+            Instruction::DEC(src) => vec![(DEC_OPCODE << 12) | ((*src) << 4 & 0x0F0)],
+            Instruction::PRINT(src) => vec![(PRINT_OPCODE << 12) | ((*src) << 4 & 0x0F0)],
             Instruction::POW(dst, value) => {
-                (POW_OPCODE << 12) | ((*dst) << 8 & 0xF00) | (*value & 0xFF)
+                vec![(POW_OPCODE << 12) | ((*dst) << 8 & 0xF00) | (*value & 0xFF)]
             }
             Instruction::MOVR(dst, src) => {
-                (MOVR_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+                vec![(MOVR_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)]
             }
             Instruction::CMP(dst, src) => {
-                (CMP_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)
+                vec![(CMP_OPCODE << 12) | ((*dst) << 8 & 0xF00) | ((*src) << 4 & 0x0F0)]
+            }
+            Instruction::JMP(address) => vec![encode_jump(COND_ALWAYS, *address)],
+            Instruction::JZ(address) => vec![encode_jump(COND_ZERO, *address)],
+            Instruction::JNZ(address) => vec![encode_jump(COND_NOT_ZERO, *address)],
+            Instruction::JC(address) => vec![encode_jump(COND_CARRY, *address)],
+            Instruction::JNC(address) => vec![encode_jump(COND_NOT_CARRY, *address)],
+            Instruction::JN(address) => vec![encode_jump(COND_NEGATIVE, *address)],
+            Instruction::JNN(address) => vec![encode_jump(COND_NOT_NEGATIVE, *address)],
+            Instruction::JO(address) => vec![encode_jump(COND_OVERFLOW, *address)],
+            Instruction::JNO(address) => vec![encode_jump(COND_NOT_OVERFLOW, *address)],
+            Instruction::JA(address) => vec![encode_jump(COND_ABOVE, *address)],
+            Instruction::JBE(address) => vec![encode_jump(COND_BELOW_EQUAL, *address)],
+            Instruction::JL(address) => vec![encode_jump(COND_LESS, *address)],
+            Instruction::JGE(address) => vec![encode_jump(COND_GREATER_EQUAL, *address)],
+            Instruction::JG(address) => vec![encode_jump(COND_GREATER, *address)],
+            Instruction::JLE(address) => vec![encode_jump(COND_LESS_EQUAL, *address)],
+            Instruction::HALT => vec![HALT_OPCODE << 12],
+            Instruction::LOAD(reg, address) => vec![encode_extended(*reg, EXT_LOAD), *address],
+            Instruction::LOADX(reg, base, offset) => vec![
+                encode_extended(*reg, EXT_LOAD_INDEXED),
+                (*base << 12) | (*offset & 0xFF),
+            ],
+            Instruction::STORE(address, reg) => {
+                vec![encode_extended(*reg, EXT_STORE), *address]
             }
-            Instruction::JMP(src) => (JMP_OPCODE << 12) | ((*src) << 8 & 0xF00),
-            Instruction::HALT => HALT_OPCODE << 12,
-            Instruction::NOP => NOP_OPCODE << 12,
+            Instruction::STOREX(base, offset, reg) => vec![
+                encode_extended(*reg, EXT_STORE_INDEXED),
+                (*base << 12) | (*offset & 0xFF),
+            ],
+            Instruction::IMUL(dst, src) => vec![encode_extended(*dst, EXT_IMUL), *src << 12],
+            Instruction::IDIV(dst, src) => vec![encode_extended(*dst, EXT_IDIV), *src << 12],
+            Instruction::ICMP(dst, src) => vec![encode_extended(*dst, EXT_ICMP), *src << 12],
+            Instruction::AND(dst, src) => vec![encode_extended(*dst, EXT_AND), *src << 12],
+            Instruction::OR(dst, src) => vec![encode_extended(*dst, EXT_OR), *src << 12],
+            Instruction::XOR(dst, src) => vec![encode_extended(*dst, EXT_XOR), *src << 12],
+            Instruction::NOT(reg) => vec![encode_extended(*reg, EXT_NOT)],
+            Instruction::SHL(reg, count) => vec![encode_extended(*reg, EXT_SHL), *count],
+            Instruction::SHR(reg, count) => vec![encode_extended(*reg, EXT_SHR), *count],
+            Instruction::ROL(reg, count) => vec![encode_extended(*reg, EXT_ROL), *count],
+            Instruction::ROR(reg, count) => vec![encode_extended(*reg, EXT_ROR), *count],
+            Instruction::IN(reg) => vec![encode_extended(*reg, EXT_IN)],
+            Instruction::PUSH(reg) => vec![encode_extended(*reg, EXT_PUSH)],
+            Instruction::POP(reg) => vec![encode_extended(*reg, EXT_POP)],
+            Instruction::CALL(address) => vec![encode_extended(0, EXT_CALL), *address],
+            Instruction::RET => vec![encode_extended(0, EXT_RET)],
+            Instruction::NOP => vec![encode_extended(0, EXT_NOP)],
+            Instruction::WORD(value) => vec![*value],
         }
     }
 
-    pub fn fetch_instruction(&mut self) -> Option<u16> {
-        if self.pc < MEMORY_SIZE as u16 {
-            let config = declare_config();
+    /// Fetches the next instruction, along with its operand word (0 for one-word instructions).
+    pub fn fetch_instruction(&mut self) -> Option<(u16, u16)> {
+        let length = instruction_length(*self.memory.get(self.pc as usize)?);
+        if self.pc as usize + length as usize <= MEMORY_SIZE {
             let instruction = self.memory[self.pc as usize];
-            self.pc += 1;
+            let operand = if length == 2 {
+                self.memory[self.pc as usize + 1]
+            } else {
+                0
+            };
+            self.pc += length;
 
-            if config.verbose_debug {
+            if self.verbose_debug {
                 println!("Program Counter: {:?}", self.pc);
                 println!("Instruction: {:?}", instruction);
+                if length == 2 {
+                    println!("Operand: {:?}", operand);
+                }
             }
-            Some(instruction)
+            Some((instruction, operand))
         } else {
             None
         }
@@ -103,103 +219,696 @@ impl CPU {
         }
     }
 
-    pub fn print_register(&self, index: u16) {
-        match self.get_register(index) {
-            Some(value) => println!("{}x: {}", integer_to_letter(index as usize), value),
-            None => println!("Register index {} is out of bounds.", index),
+    /// Formats a register value, as a signed number in the signed arithmetic mode.
+    pub fn format_value(&self, value: u16) -> String {
+        if self.arithmetic == ArithmeticMode::Signed {
+            (value as i16).to_string()
+        } else {
+            value.to_string()
         }
     }
 
-    pub fn execute_instruction(&mut self, instruction: u16) {
+    pub fn print_register(&mut self, index: u16) {
+        let line = match (self.get_register(index), integer_to_letter(index as usize)) {
+            (Some(value), Some(letter)) => format!("{}x: {}", letter, self.format_value(value)),
+            _ => format!("Register index {} is out of bounds.", index),
+        };
+        match &mut self.output {
+            Some(output) => output.push(line),
+            None => println!("{}", line),
+        }
+    }
+
+    pub fn get_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn set_flag(&mut self, flag: u16, set: bool) {
+        if set {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    /// Updates every status flag after an arithmetic operation.
+    fn set_arith_flags(&mut self, result: u16, carry: bool, overflow: bool) {
+        self.set_flag(FLAG_ZERO, result == 0);
+        self.set_flag(FLAG_CARRY, carry);
+        self.set_flag(FLAG_NEGATIVE, result & 0x8000 != 0);
+        self.set_flag(FLAG_OVERFLOW, overflow);
+    }
+
+    /// Stores the result of an arithmetic instruction according to the arithmetic mode, and
+    /// updates the flags. `carry` and `overflow` tell whether the wrapped result overflowed as an
+    /// unsigned or a signed number. Trapping leaves the register and flags untouched.
+    fn store_arith(
+        &mut self,
+        reg: u16,
+        name: &'static str,
+        wrapped: u16,
+        saturated: u16,
+        carry: bool,
+        overflow: bool,
+    ) -> Result<(), Fault> {
+        let result = match self.arithmetic {
+            ArithmeticMode::Wrap => wrapped,
+            ArithmeticMode::Saturate => saturated,
+            ArithmeticMode::Trap if carry && matches!(name, "SUB" | "DEC") => {
+                return Err(Fault::NegativeResult(name));
+            }
+            ArithmeticMode::Trap if carry => return Err(Fault::Overflow(name)),
+            ArithmeticMode::Signed if overflow => return Err(Fault::Overflow(name)),
+            ArithmeticMode::Trap | ArithmeticMode::Signed => wrapped,
+        };
+        self.registers[reg as usize] = result;
+        self.set_arith_flags(result, carry, overflow);
+        Ok(())
+    }
+
+    /// Stores the result of a bitwise instruction, which never overflows.
+    fn store_bitwise(&mut self, reg: usize, result: u16, carry: bool) {
+        self.registers[reg] = result;
+        self.set_arith_flags(result, carry, false);
+    }
+
+    /// Checks a jump condition against the flags, or returns None if the condition is unknown.
+    pub fn condition_met(&self, condition: u16) -> Option<bool> {
+        let zero = self.get_flag(FLAG_ZERO);
+        let carry = self.get_flag(FLAG_CARRY);
+        let negative = self.get_flag(FLAG_NEGATIVE);
+        let overflow = self.get_flag(FLAG_OVERFLOW);
+        match condition {
+            COND_ALWAYS => Some(true),
+            COND_ZERO => Some(zero),
+            COND_NOT_ZERO => Some(!zero),
+            COND_CARRY => Some(carry),
+            COND_NOT_CARRY => Some(!carry),
+            COND_NEGATIVE => Some(negative),
+            COND_NOT_NEGATIVE => Some(!negative),
+            COND_OVERFLOW => Some(overflow),
+            COND_NOT_OVERFLOW => Some(!overflow),
+            COND_ABOVE => Some(!carry && !zero),
+            COND_BELOW_EQUAL => Some(carry || zero),
+            COND_LESS => Some(negative != overflow),
+            COND_GREATER_EQUAL => Some(negative == overflow),
+            COND_GREATER => Some(!zero && negative == overflow),
+            COND_LESS_EQUAL => Some(zero || negative != overflow),
+            _ => None,
+        }
+    }
+
+    pub fn execute_instruction(&mut self, instruction: u16, operand: u16) -> Result<(), Fault> {
         let opcode = instruction >> 12;
         let reg1 = (instruction >> 8) & 0xF; // Change to u16
         let reg2 = (instruction >> 4) & 0xF; // Change to u16
         let value = instruction & 0xFF;
+        let dst = self.registers[reg1 as usize];
+        let src = self.registers[reg2 as usize];
 
         match opcode {
-            ADD_OPCODE => self.registers[reg1 as usize] += self.registers[reg2 as usize],
-            MOV_OPCODE => self.registers[reg1 as usize] = value,
-            MUL_OPCODE => self.registers[reg1 as usize] *= self.registers[reg2 as usize],
-            SUB_OPCODE => {
-                if self.registers[reg1 as usize] >= self.registers[reg2 as usize] {
-                    self.registers[reg1 as usize] -= self.registers[reg2 as usize];
+            ADD_OPCODE => {
+                let (result, carry) = dst.overflowing_add(src);
+                let overflow = (dst as i16).overflowing_add(src as i16).1;
+                let saturated = dst.saturating_add(src);
+                self.store_arith(reg1, "ADD", result, saturated, carry, overflow)?;
+            }
+            MOV_OPCODE => {
+                self.registers[reg1 as usize] = if self.arithmetic == ArithmeticMode::Signed {
+                    value as u8 as i8 as u16 // Sign extend the 8 bit immediate
                 } else {
-                    neg_num_err("SUB");
-                }
+                    value
+                };
+            }
+            MUL_OPCODE => {
+                let (result, carry) = dst.overflowing_mul(src);
+                let overflow = (dst as i16).overflowing_mul(src as i16).1;
+                let saturated = dst.saturating_mul(src);
+                self.store_arith(reg1, "MUL", result, saturated, carry, overflow)?;
+            }
+            SUB_OPCODE => {
+                let (result, borrow) = dst.overflowing_sub(src);
+                let overflow = (dst as i16).overflowing_sub(src as i16).1;
+                let saturated = dst.saturating_sub(src);
+                self.store_arith(reg1, "SUB", result, saturated, borrow, overflow)?;
             }
             SWAP_OPCODE => self.registers.swap(reg1 as usize, reg2 as usize),
             DIV_OPCODE => {
-                if self.registers[reg2 as usize] != 0 {
-                    self.registers[reg1 as usize] /= self.registers[reg2 as usize];
-                } else {
-                    self.running = false;
-                    err_print("Dividing by zero is not allowed.".to_string());
-                }
+                let quotient = dst.checked_div(src).ok_or(Fault::DivideByZero)?;
+                self.registers[reg1 as usize] = quotient;
+                self.set_arith_flags(quotient, false, false);
             }
             CLR_OPCODE => self.registers[reg2 as usize] = 0,
-            INC_OPCODE => self.registers[reg2 as usize] += 1,
+            INC_OPCODE => {
+                let (result, carry) = src.overflowing_add(1);
+                let overflow = (src as i16).overflowing_add(1).1;
+                self.store_arith(reg2, "INC", result, src.saturating_add(1), carry, overflow)?;
+            }
             DEC_OPCODE => {
-                if self.registers[reg2 as usize] >= 1 {
-                    self.registers[reg2 as usize] -= 1;
-                } else {
-                    neg_num_err("DEC");
-                }
+                let (result, borrow) = src.overflowing_sub(1);
+                let overflow = (src as i16).overflowing_sub(1).1;
+                self.store_arith(reg2, "DEC", result, src.saturating_sub(1), borrow, overflow)?;
             }
             PRINT_OPCODE => self.print_register(reg2),
             POW_OPCODE => {
-                self.registers[reg1 as usize] =
-                    u16::pow(self.registers[reg1 as usize], value.into())
+                let (result, carry) = dst.overflowing_pow(value.into());
+                let overflow = (dst as i16).overflowing_pow(value.into()).1;
+                let saturated = dst.saturating_pow(value.into());
+                self.store_arith(reg1, "POW", result, saturated, carry, overflow)?;
             }
-            MOVR_OPCODE => self.registers[reg1 as usize] = self.registers[reg2 as usize],
+            MOVR_OPCODE => self.registers[reg1 as usize] = src,
             CMP_OPCODE => {
-                self.zflag = self.registers[reg1 as usize] == self.registers[reg2 as usize];
+                // Flags are set as if SRC was subtracted from DEST, without storing the result
+                let (result, borrow) = dst.overflowing_sub(src);
+                self.set_arith_flags(result, borrow, (dst as i16).overflowing_sub(src as i16).1);
             }
             JMP_OPCODE => {
                 // Here, we interpret `value` as the new program counter (PC) address
-                let jump_address = value; // Ensure this is cast correctly
-                self.pc = jump_address; // Update the program counter to the jump address
+                if self.condition_met(reg1).ok_or(Fault::UnknownInstruction)? {
+                    self.pc = value;
+                }
             }
-            NOP_OPCODE => {}
-            _ => self.running = false,
+            EXT_OPCODE => self.execute_extended(instruction, operand)?,
+            HALT_OPCODE => self.running = false,
+            _ => return Err(Fault::UnknownInstruction),
         }
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        self.running = true;
-        while self.running {
-            if let Some(instruction) = self.fetch_instruction() {
-                self.execute_instruction(instruction);
-            } else {
-                self.running = false;
-            }
+    /// Computes base register + offset for an indexed memory operand.
+    fn indexed_address(&self, operand: u16) -> u16 {
+        self.registers[(operand >> 12) as usize].wrapping_add(operand & 0xFF)
+    }
+
+    pub fn read_memory(&self, address: u16) -> Result<u16, Fault> {
+        self.memory
+            .get(address as usize)
+            .copied()
+            .ok_or(Fault::MemoryOutOfBounds(address))
+    }
+
+    pub fn write_memory(&mut self, address: u16, value: u16) -> Result<(), Fault> {
+        let cell = self
+            .memory
+            .get_mut(address as usize)
+            .ok_or(Fault::MemoryOutOfBounds(address))?;
+        *cell = value;
+        Ok(())
+    }
+
+    /// Moves the stack pointer, which can be anywhere from the start to the end of memory.
+    pub fn set_sp(&mut self, sp: u16) -> Result<(), Fault> {
+        if sp as usize > MEMORY_SIZE {
+            return Err(Fault::StackPointerOutOfBounds(sp));
+        }
+        self.sp = sp;
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: u16) -> Result<(), Fault> {
+        // The SP can be set from a debugger, so it isn't always inside memory
+        if self.sp as usize > MEMORY_SIZE {
+            return Err(Fault::StackPointerOutOfBounds(self.sp));
+        }
+        if self.sp <= self.stack_limit {
+            return Err(Fault::StackOverflow);
+        }
+        self.sp -= 1;
+        self.memory[self.sp as usize] = value;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<u16, Fault> {
+        if self.sp as usize > MEMORY_SIZE {
+            return Err(Fault::StackPointerOutOfBounds(self.sp));
         }
+        if self.sp as usize == MEMORY_SIZE {
+            return Err(Fault::StackUnderflow);
+        }
+        let value = self.memory[self.sp as usize];
+        self.sp += 1;
+        Ok(value)
     }
 
-    pub fn emit_binary(&self, filename: &str) -> io::Result<()> {
-        let mut file = File::create(filename)?;
-        for &instruction in &self.memory {
-            if instruction != 0 {
-                file.write_all(&instruction.to_be_bytes())?;
+    /// Executes an instruction from the extended opcode space.
+    fn execute_extended(&mut self, instruction: u16, operand: u16) -> Result<(), Fault> {
+        let reg = ((instruction >> 8) & 0xF) as usize;
+
+        let dst = self.registers[reg] as i16;
+        let src = self.registers[(operand >> 12) as usize] as i16;
+
+        match instruction & 0xFF {
+            EXT_NOP => {}
+            EXT_IMUL => {
+                let (result, overflow) = dst.overflowing_mul(src);
+                let saturated = dst.saturating_mul(src) as u16;
+                self.store_arith(
+                    reg as u16,
+                    "IMUL",
+                    result as u16,
+                    saturated,
+                    overflow,
+                    overflow,
+                )?;
+            }
+            EXT_IDIV => {
+                if src == 0 {
+                    return Err(Fault::DivideByZero);
+                }
+                // Only i16::MIN / -1 can overflow
+                let (result, overflow) = dst.overflowing_div(src);
+                let saturated = dst.saturating_div(src) as u16;
+                self.store_arith(
+                    reg as u16,
+                    "IDIV",
+                    result as u16,
+                    saturated,
+                    overflow,
+                    overflow,
+                )?;
+            }
+            EXT_ICMP => {
+                // Like CMP, but C is set when DEST is less than SRC as a signed number
+                let (result, overflow) = dst.overflowing_sub(src);
+                self.set_arith_flags(result as u16, dst < src, overflow);
+            }
+            EXT_AND => self.store_bitwise(reg, dst as u16 & src as u16, false),
+            EXT_OR => self.store_bitwise(reg, dst as u16 | src as u16, false),
+            EXT_XOR => self.store_bitwise(reg, dst as u16 ^ src as u16, false),
+            EXT_NOT => self.store_bitwise(reg, !dst as u16, false),
+            EXT_SHL | EXT_SHR | EXT_ROL | EXT_ROR => {
+                let value = dst as u16;
+                let count = (operand & 0xF) as u32;
+                let result = match instruction & 0xFF {
+                    EXT_SHL => value.checked_shl(count).unwrap_or(0),
+                    EXT_SHR => value.checked_shr(count).unwrap_or(0),
+                    EXT_ROL => value.rotate_left(count),
+                    _ => value.rotate_right(count),
+                };
+                // C holds the last bit shifted out, which rotates also move to the other end
+                let carry = count > 0
+                    && match instruction & 0xFF {
+                        EXT_SHL => value >> (16 - count) & 1 != 0,
+                        EXT_SHR => value >> (count - 1) & 1 != 0,
+                        EXT_ROL => result & 1 != 0,
+                        _ => result & 0x8000 != 0,
+                    };
+                self.store_bitwise(reg, result, carry);
             }
+            EXT_PUSH => self.push(self.registers[reg])?,
+            EXT_POP => self.registers[reg] = self.pop()?,
+            EXT_CALL => {
+                self.push(self.pc)?;
+                self.pc = operand;
+            }
+            EXT_RET => self.pc = self.pop()?,
+            EXT_IN => self.registers[reg] = self.input.pop_front().ok_or(Fault::NoInput)?,
+            EXT_LOAD => self.registers[reg] = self.read_memory(operand)?,
+            EXT_LOAD_INDEXED => {
+                self.registers[reg] = self.read_memory(self.indexed_address(operand))?;
+            }
+            EXT_STORE => self.write_memory(operand, self.registers[reg])?,
+            EXT_STORE_INDEXED => {
+                self.write_memory(self.indexed_address(operand), self.registers[reg])?
+            }
+            _ => return Err(Fault::UnknownInstruction),
         }
         Ok(())
     }
 
-    pub fn load_binary(&mut self, filename: &str) -> io::Result<()> {
-        let mut file = File::open(filename)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+    /// Executes a single instruction and reports what happened.
+    pub fn step(&mut self) -> StepResult {
+        if !self.running {
+            return StepResult::Halted;
+        }
 
-        for (i, chunk) in buffer.chunks_exact(2).enumerate() {
-            if i < MEMORY_SIZE {
-                let instruction = u16::from_be_bytes([chunk[0], chunk[1]]);
-                self.memory[i] = instruction;
-            } else {
-                eprintln!("Warning: Binary exceeds memory size.");
+        let pc = self.pc;
+        let next = self.memory.get(pc as usize).copied().unwrap_or(HALT_OPCODE);
+        if next >> 12 == EXT_OPCODE && next & 0xFF == EXT_IN && self.input.is_empty() {
+            return StepResult::WaitingForInput; // Nothing is executed, so the IN can be retried
+        }
+
+        let Some((instruction, operand)) = self.fetch_instruction() else {
+            self.running = false; // Ran off the end of memory
+            return StepResult::Halted;
+        };
+        if let Err(fault) = self.execute_instruction(instruction, operand) {
+            self.running = false;
+            self.pc = pc;
+            return StepResult::Fault(CpuError {
+                fault,
+                pc,
+                instruction,
+                operand,
+            });
+        }
+
+        if !self.running {
+            StepResult::Halted
+        } else if self.breakpoints.contains(&self.pc) {
+            StepResult::Breakpoint(self.pc)
+        } else {
+            StepResult::Executed
+        }
+    }
+
+    /// Steps at most `budget` instructions, stopping early on anything but `Executed`.
+    /// Returns the result of the last step, which is `Executed` if the budget ran out.
+    pub fn run_for(&mut self, budget: usize) -> StepResult {
+        let mut result = StepResult::Executed;
+        for _ in 0..budget {
+            result = self.step();
+            if result != StepResult::Executed {
                 break;
             }
         }
-        self.pc = 0;
+        result
+    }
+
+    /// Runs until the program halts, or returns the fault that stopped it.
+    /// Breakpoints are ignored, and input for IN is read from stdin.
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        loop {
+            match self.step() {
+                StepResult::Executed | StepResult::Breakpoint(_) => {}
+                StepResult::Halted => return Ok(()),
+                StepResult::Fault(error) => return Err(error),
+                StepResult::WaitingForInput => {
+                    if let Some(value) = self.read_input() {
+                        self.input.push_back(value);
+                    } else {
+                        // Stdin is closed, so the IN can never run
+                        self.running = false;
+                        return Err(CpuError {
+                            fault: Fault::NoInput,
+                            pc: self.pc,
+                            instruction: self.memory[self.pc as usize],
+                            operand: 0,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads a number for IN from stdin, asking again until it gets one. None if stdin is closed.
+    fn read_input(&self) -> Option<u16> {
+        let stdin = io::stdin();
+        loop {
+            print!("{}", "INPUT: ".color(Colors::BlueFg));
+            io::stdout().flush().ok()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            let line = line.trim();
+            if let Ok(value) = line.parse::<u16>() {
+                return Some(value);
+            }
+            if let Ok(value) = line.parse::<i16>() {
+                return Some(value as u16); // Negative numbers are stored as two's complement
+            }
+            println!("Not a number: \"{}\"", line);
+        }
+    }
+
+    /// Loads a binary written with `Binary::write`, starting at its entry point.
+    pub fn load_binary(&mut self, filename: &str) -> Result<(), Error> {
+        let binary = Binary::read(filename)?;
+        for section in &binary.sections {
+            let start = section.address as usize;
+            self.memory[start..start + section.words.len()].copy_from_slice(&section.words);
+            self.stack_limit = self.stack_limit.max((start + section.words.len()) as u16);
+        }
+        self.pc = binary.entry;
         Ok(())
     }
 }
+
+/// Prints the address, raw words and assembly of every instruction in a run of code,
+/// which starts at address `start`.
+pub fn disassemble(words: &[u16], start: usize) {
+    let mut address = 0;
+    while address < words.len() {
+        let word = words[address];
+        let operand = words.get(address + 1).copied();
+        let instruction = match operand {
+            Some(operand) if instruction_length(word) == 2 => decode_instruction(word, operand),
+            None if instruction_length(word) == 2 => Instruction::WORD(word), // Cut off
+            _ => decode_instruction(word, 0),
+        };
+        // Two-word instructions that don't decode only take up their first word
+        let length = match instruction {
+            Instruction::WORD(_) => 1,
+            _ => instruction_length(word) as usize,
+        };
+        let raw = match length {
+            2 => format!("{:04x} {:04x}", word, words[address + 1]),
+            _ => format!("{:04x}", word),
+        };
+        println!("{:>3}: {:<10} {}", start + address, raw, instruction);
+        address += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs one instruction in an arithmetic mode, with ax and bx holding the given values.
+    fn run_one(
+        mode: ArithmeticMode,
+        instruction: Instruction,
+        ax: u16,
+        bx: u16,
+    ) -> (CPU, StepResult) {
+        let mut cpu = CPU::new();
+        cpu.arithmetic = mode;
+        cpu.load_program(&[instruction, Instruction::HALT]);
+        cpu.registers[0] = ax;
+        cpu.registers[1] = bx;
+        let result = cpu.step();
+        (cpu, result)
+    }
+
+    /// The fault an instruction stopped with, if any.
+    fn fault(result: StepResult) -> Option<Fault> {
+        match result {
+            StepResult::Fault(error) => Some(error.fault),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn stack_holds_stack_size_words() {
+        let mut cpu = CPU::new();
+        for value in 0..STACK_SIZE as u16 {
+            assert_eq!(cpu.push(value), Ok(()));
+        }
+        assert_eq!(cpu.push(0), Err(Fault::StackOverflow));
+        assert_eq!(cpu.pop(), Ok(STACK_SIZE as u16 - 1));
+    }
+
+    #[test]
+    fn push_never_overwrites_the_program() {
+        let mut cpu = CPU::new();
+        let program: Vec<Instruction> =
+            (0..MEMORY_SIZE - 2).map(|_| Instruction::WORD(1)).collect();
+        cpu.load_program(&program);
+        assert_eq!(cpu.push(7), Ok(()));
+        assert_eq!(cpu.push(7), Ok(()));
+        assert_eq!(cpu.push(7), Err(Fault::StackOverflow));
+        assert_eq!(cpu.memory[MEMORY_SIZE - 3], 1);
+    }
+
+    #[test]
+    fn stack_pointer_stays_inside_memory() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.set_sp(1000), Err(Fault::StackPointerOutOfBounds(1000)));
+        assert_eq!(cpu.sp, MEMORY_SIZE as u16);
+
+        // The field itself can still be written, so the stack checks it too
+        cpu.load_program(&[Instruction::PUSH(0), Instruction::POP(0)]);
+        cpu.sp = 1000;
+        assert_eq!(cpu.pop(), Err(Fault::StackPointerOutOfBounds(1000)));
+        match cpu.step() {
+            StepResult::Fault(error) => {
+                assert_eq!(error.fault, Fault::StackPointerOutOfBounds(1000));
+                assert_eq!(error.pc, 0);
+            }
+            result => panic!("expected a fault, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn wraps_and_sets_flags_at_the_boundaries() {
+        use ArithmeticMode::Wrap;
+        let (cpu, _) = run_one(Wrap, Instruction::ADD(0, 1), 0xFFFF, 1);
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(format_flags(&cpu), "Z=1 C=1 N=0 V=0");
+
+        let (cpu, _) = run_one(Wrap, Instruction::ADD(0, 1), 0x7FFF, 1);
+        assert_eq!(cpu.registers[0], 0x8000);
+        assert_eq!(format_flags(&cpu), "Z=0 C=0 N=1 V=1");
+
+        let (cpu, _) = run_one(Wrap, Instruction::SUB(0, 1), 0, 1);
+        assert_eq!(cpu.registers[0], 0xFFFF);
+        assert_eq!(format_flags(&cpu), "Z=0 C=1 N=1 V=0");
+
+        let (cpu, _) = run_one(Wrap, Instruction::SUB(0, 1), 0x8000, 1);
+        assert_eq!(cpu.registers[0], 0x7FFF);
+        assert_eq!(format_flags(&cpu), "Z=0 C=0 N=0 V=1");
+
+        let (cpu, _) = run_one(Wrap, Instruction::MUL(0, 1), 0x100, 0x100);
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(format_flags(&cpu), "Z=1 C=1 N=0 V=1");
+    }
+
+    #[test]
+    fn saturates_at_the_ends_of_the_unsigned_range() {
+        use ArithmeticMode::Saturate;
+        let (cpu, _) = run_one(Saturate, Instruction::ADD(0, 1), 0xFFFF, 1);
+        assert_eq!(cpu.registers[0], 0xFFFF);
+        assert_eq!(format_flags(&cpu), "Z=0 C=1 N=1 V=0");
+
+        let (cpu, _) = run_one(Saturate, Instruction::SUB(0, 1), 0, 1);
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(format_flags(&cpu), "Z=1 C=1 N=0 V=0");
+
+        let (cpu, _) = run_one(Saturate, Instruction::DEC(0), 0, 0);
+        assert_eq!(cpu.registers[0], 0);
+
+        let (cpu, _) = run_one(Saturate, Instruction::POW(0, 3), 100, 0);
+        assert_eq!(cpu.registers[0], 0xFFFF);
+    }
+
+    #[test]
+    fn traps_on_unsigned_overflow_and_negative_results() {
+        use ArithmeticMode::Trap;
+        let (cpu, result) = run_one(Trap, Instruction::ADD(0, 1), 0xFFFF, 1);
+        assert_eq!(fault(result), Some(Fault::Overflow("ADD")));
+        assert_eq!(cpu.registers[0], 0xFFFF); // Left as it was
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(format_flags(&cpu), "Z=0 C=0 N=0 V=0");
+
+        let (_, result) = run_one(Trap, Instruction::SUB(0, 1), 0, 1);
+        assert_eq!(fault(result), Some(Fault::NegativeResult("SUB")));
+        let (_, result) = run_one(Trap, Instruction::DEC(0), 0, 0);
+        assert_eq!(fault(result), Some(Fault::NegativeResult("DEC")));
+        let (_, result) = run_one(Trap, Instruction::INC(0), 0xFFFF, 0);
+        assert_eq!(fault(result), Some(Fault::Overflow("INC")));
+
+        // Signed overflow alone is fine, since the numbers are unsigned
+        let (cpu, result) = run_one(Trap, Instruction::ADD(0, 1), 0x7FFF, 1);
+        assert_eq!(result, StepResult::Executed);
+        assert_eq!(cpu.registers[0], 0x8000);
+        assert_eq!(format_flags(&cpu), "Z=0 C=0 N=1 V=1");
+    }
+
+    #[test]
+    fn traps_on_signed_overflow_in_the_signed_mode() {
+        use ArithmeticMode::Signed;
+        let (cpu, result) = run_one(Signed, Instruction::ADD(0, 1), 0x7FFF, 1);
+        assert_eq!(fault(result), Some(Fault::Overflow("ADD")));
+        assert_eq!(cpu.registers[0], 0x7FFF);
+
+        let (_, result) = run_one(Signed, Instruction::SUB(0, 1), 0x8000, 1);
+        assert_eq!(fault(result), Some(Fault::Overflow("SUB")));
+
+        // -1 + 1 and 0 - 1 carry as unsigned numbers, but are fine as signed ones
+        let (cpu, result) = run_one(Signed, Instruction::ADD(0, 1), 0xFFFF, 1);
+        assert_eq!(result, StepResult::Executed);
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(format_flags(&cpu), "Z=1 C=1 N=0 V=0");
+
+        let (cpu, _) = run_one(Signed, Instruction::SUB(0, 1), 0, 1);
+        assert_eq!(cpu.registers[0], 0xFFFF);
+        assert_eq!(format_flags(&cpu), "Z=0 C=1 N=1 V=0");
+    }
+
+    #[test]
+    fn sign_extends_mov_only_in_the_signed_mode() {
+        let (cpu, _) = run_one(ArithmeticMode::Signed, Instruction::MOV(0, 0xFB), 0, 0);
+        assert_eq!(cpu.registers[0], (-5i16) as u16);
+        let (cpu, _) = run_one(ArithmeticMode::Wrap, Instruction::MOV(0, 0xFB), 0, 0);
+        assert_eq!(cpu.registers[0], 251);
+    }
+
+    /// Shifts or rotates ax, starting with C set, and returns ax and the carry.
+    fn shift(instruction: Instruction, ax: u16) -> (u16, bool) {
+        let mut cpu = CPU::new();
+        cpu.load_program(&[instruction, Instruction::HALT]);
+        cpu.registers[0] = ax;
+        cpu.set_flag(FLAG_CARRY, true);
+        assert_eq!(cpu.step(), StepResult::Executed);
+        (cpu.registers[0], cpu.get_flag(FLAG_CARRY))
+    }
+
+    #[test]
+    fn shifts_the_last_bit_out_into_carry() {
+        assert_eq!(shift(Instruction::SHL(0, 1), 0x8001), (0x0002, true));
+        assert_eq!(shift(Instruction::SHL(0, 1), 0x4001), (0x8002, false));
+        assert_eq!(shift(Instruction::SHR(0, 1), 0x0003), (0x0001, true));
+        assert_eq!(shift(Instruction::SHR(0, 2), 0x0002), (0x0000, true));
+        assert_eq!(shift(Instruction::SHR(0, 2), 0x0004), (0x0001, false));
+    }
+
+    #[test]
+    fn rotates_the_bit_around_into_carry() {
+        assert_eq!(shift(Instruction::ROL(0, 1), 0x8000), (0x0001, true));
+        assert_eq!(shift(Instruction::ROL(0, 4), 0x1234), (0x2341, true));
+        assert_eq!(shift(Instruction::ROR(0, 1), 0x0001), (0x8000, true));
+        assert_eq!(shift(Instruction::ROR(0, 4), 0x1234), (0x4123, false));
+    }
+
+    #[test]
+    fn shifts_by_zero_clear_carry_and_keep_the_value() {
+        for instruction in [
+            Instruction::SHL(0, 0),
+            Instruction::SHR(0, 0),
+            Instruction::ROL(0, 0),
+            Instruction::ROR(0, 0),
+        ] {
+            assert_eq!(shift(instruction, 0x8001), (0x8001, false));
+        }
+    }
+
+    #[test]
+    fn shifts_by_fifteen() {
+        assert_eq!(shift(Instruction::SHL(0, 15), 0x0003), (0x8000, true));
+        assert_eq!(shift(Instruction::SHL(0, 15), 0x0001), (0x8000, false));
+        assert_eq!(shift(Instruction::SHR(0, 15), 0xC000), (0x0001, true));
+        assert_eq!(shift(Instruction::SHR(0, 15), 0x8000), (0x0001, false));
+        // Rotating by 15 is rotating by 1 the other way
+        assert_eq!(shift(Instruction::ROL(0, 15), 0x0001), (0x8000, false));
+        assert_eq!(shift(Instruction::ROR(0, 15), 0x4000), (0x8000, true));
+    }
+
+    #[test]
+    fn reset_keeps_breakpoints_and_output_collection() {
+        let program = [Instruction::PRINT(0), Instruction::HALT];
+        let mut cpu = CPU::new();
+        cpu.output = Some(Vec::new());
+        cpu.breakpoints.insert(1);
+        cpu.reset(&program, ArithmeticMode::Signed);
+        assert_eq!(cpu.step(), StepResult::Breakpoint(1));
+        cpu.registers[0] = 9;
+
+        cpu.reset(&program, ArithmeticMode::Wrap);
+        assert_eq!(cpu.registers[0], 0);
+        assert_eq!(cpu.arithmetic, ArithmeticMode::Wrap);
+        assert_eq!(cpu.output, Some(Vec::new()));
+        assert!(cpu.breakpoints.contains(&1));
+    }
+
+    #[test]
+    fn set_pc_runs_again_after_a_halt() {
+        let mut cpu = CPU::new();
+        cpu.load_program(&[Instruction::HALT, Instruction::INC(0), Instruction::HALT]);
+        assert_eq!(cpu.step(), StepResult::Halted);
+        assert_eq!(cpu.step(), StepResult::Halted);
+        cpu.set_pc(1);
+        assert_eq!(cpu.step(), StepResult::Executed);
+        assert_eq!(cpu.registers[0], 1);
+    }
+}
@@ -0,0 +1,584 @@
+use crate::*;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Instructions run between checks for a pause request
+const PAUSE_INTERVAL: usize = 1000;
+
+// The only thread, and the only stack frame
+const THREAD_ID: i64 = 1;
+const FRAME_ID: i64 = 1;
+
+// Variable references for the two scopes
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+
+const FLAG_NAMES: [(&str, u16); 4] = [
+    ("Z", FLAG_ZERO),
+    ("C", FLAG_CARRY),
+    ("N", FLAG_NEGATIVE),
+    ("V", FLAG_OVERFLOW),
+];
+
+/// How far `resume` should run the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    StepIn,  // One instruction
+    Next,    // One instruction, running through any CALL
+    StepOut, // Until the current function returns
+}
+
+/// Why a running program has to stop before `resume` is done with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Interrupt {
+    Pause,
+    End, // The editor disconnected or asked to end the session
+}
+
+/// Reads one message, framed by a Content-Length header. None at the end of input.
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+/// A Debug Adapter Protocol server, which talks to an editor over stdin and stdout.
+pub struct DapServer {
+    seq: i64, // Sequence number of the last message sent
+    messages: Receiver<Value>,
+    deferred: VecDeque<Value>, // Requests that arrived while the program was running
+    cpu: CPU,
+    program: Option<Program>, // Set by the launch request
+    source_path: String,
+    arithmetic: ArithmeticMode,
+    stop_on_entry: bool,
+    lines_start_at1: bool,
+    exit_code: i64,
+}
+
+impl DapServer {
+    /// Starts reading requests from stdin on a separate thread, so `pause` works while running.
+    pub fn new(arithmetic: ArithmeticMode) -> DapServer {
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(io::stdin());
+            while let Some(message) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut cpu = CPU::new();
+        cpu.output = Some(Vec::new()); // PRINT output is sent to the editor
+        DapServer {
+            seq: 0,
+            messages,
+            deferred: VecDeque::new(),
+            cpu,
+            program: None,
+            source_path: String::new(),
+            arithmetic,
+            stop_on_entry: false,
+            lines_start_at1: true,
+            exit_code: 0,
+        }
+    }
+
+    /// Handles requests until the editor disconnects.
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = self
+            .deferred
+            .pop_front()
+            .or_else(|| self.messages.recv().ok())
+        {
+            if request["type"] == "request" && !self.handle_request(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        stdout.flush()
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> io::Result<()> {
+        self.event("output", json!({ "category": category, "output": text }))
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<&str>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    /// Handles one request, returning false once the session is over.
+    fn handle_request(&mut self, request: &Value) -> io::Result<bool> {
+        let args = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.lines_start_at1 = args["linesStartAt1"].as_bool().unwrap_or(true);
+                self.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsSetVariable": true,
+                        "supportsTerminateRequest": true,
+                    }),
+                )?;
+            }
+            "launch" => match self.launch(args) {
+                Ok(()) => {
+                    self.respond(request, json!({}))?;
+                    self.event("initialized", json!({}))?; // Ready for breakpoints
+                }
+                Err(message) => self.respond_error(request, &message)?,
+            },
+            "setBreakpoints" => {
+                let breakpoints = self.set_breakpoints(args);
+                self.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "setExceptionBreakpoints" => self.respond(request, json!({ "breakpoints": [] }))?,
+            "configurationDone" => {
+                self.respond(request, json!({}))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else if self.cpu.breakpoints.contains(&self.cpu.pc) {
+                    // Stepping only stops on breakpoints it lands on, not the one it starts at
+                    self.stopped("breakpoint", None)?;
+                } else {
+                    self.resume(Resume::Continue)?;
+                }
+            }
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            )?,
+            "stackTrace" => {
+                let frames = self.stack_frames();
+                self.respond(request, json!({ "stackFrames": frames, "totalFrames": 1 }))?;
+            }
+            "scopes" => self.respond(
+                request,
+                json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+                ] }),
+            )?,
+            "variables" => {
+                let variables = self.variables(args["variablesReference"].as_i64());
+                self.respond(request, json!({ "variables": variables }))?;
+            }
+            "setVariable" => match self.set_variable(args) {
+                Ok(value) => self.respond(request, json!({ "value": value }))?,
+                Err(message) => self.respond_error(request, &message)?,
+            },
+            "evaluate" => match self.evaluate(args["expression"].as_str().unwrap_or("")) {
+                Ok(result) => self.respond(
+                    request,
+                    json!({ "result": result, "variablesReference": 0 }),
+                )?,
+                Err(message) => self.respond_error(request, &message)?,
+            },
+            "continue" => {
+                self.respond(request, json!({ "allThreadsContinued": true }))?;
+                self.resume(Resume::Continue)?;
+            }
+            "stepIn" => {
+                self.respond(request, json!({}))?;
+                self.resume(Resume::StepIn)?;
+            }
+            "next" => {
+                self.respond(request, json!({}))?;
+                self.resume(Resume::Next)?;
+            }
+            "stepOut" => {
+                self.respond(request, json!({}))?;
+                self.resume(Resume::StepOut)?;
+            }
+            "pause" => {
+                // The program only runs inside `resume`, so it is already stopped here
+                self.respond(request, json!({}))?;
+                self.stopped("pause", None)?;
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, json!({}))?;
+                return Ok(false);
+            }
+            command => self.respond_error(request, &format!("unsupported request \"{}\"", command))?,
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("the launch configuration needs a \"program\"")?;
+        let source = fs::read_to_string(path)
+            .map_err(|error| format!("Error reading file '{}': {}", path, error))?;
+        if !args["arithmetic"].is_null() {
+            self.arithmetic = serde_json::from_value(args["arithmetic"].clone())
+                .map_err(|_| "\"arithmetic\" must be wrap, saturate, trap or signed")?;
+        }
+        let program = parse_program(path, source).map_err(|error| error.to_string())?;
+
+        self.cpu.reset(&program.instructions, self.arithmetic);
+        self.exit_code = 0;
+        self.program = Some(program);
+        self.source_path =
+            fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string());
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(())
+    }
+
+    /// Converts a source line, which starts at 1, to the editor's numbering, and back.
+    fn client_line(&self, line: usize) -> usize {
+        if self.lines_start_at1 {
+            line
+        } else {
+            line - 1
+        }
+    }
+
+    fn source_line(&self, line: usize) -> usize {
+        if self.lines_start_at1 {
+            line
+        } else {
+            line + 1
+        }
+    }
+
+    /// Checks if a path is the program's source file, which is the only one with addresses.
+    fn is_program_source(&self, path: &str) -> bool {
+        self.program.is_some()
+            && fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string())
+                == self.source_path
+    }
+
+    /// Replaces the breakpoints of a source file, moving each one to the first instruction on or
+    /// after its line. Editors send one request per file, and only the program's own file has
+    /// lines that map to addresses, so breakpoints in any other file are left unverified.
+    fn set_breakpoints(&mut self, args: &Value) -> Vec<Value> {
+        let in_program = self.is_program_source(args["source"]["path"].as_str().unwrap_or(""));
+        if in_program {
+            self.cpu.breakpoints.clear();
+        }
+        let lines: Vec<usize> = args["breakpoints"]
+            .as_array()
+            .map(|breakpoints| {
+                breakpoints
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .map(|line| self.source_line(line as usize))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            let Some(program) = self.program.as_ref().filter(|_| in_program) else {
+                breakpoints.push(json!({
+                    "verified": false,
+                    "line": self.client_line(line),
+                    "message": "breakpoints can only be set in the program being debugged",
+                }));
+                continue;
+            };
+            match program.address_of_line(line) {
+                Some(address) => {
+                    self.cpu.breakpoints.insert(address);
+                    let line = program.line_at(address).unwrap_or(line);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "line": self.client_line(line),
+                    }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "line": self.client_line(line),
+                    "message": "there is no instruction on or after this line",
+                })),
+            }
+        }
+        breakpoints
+    }
+
+    fn stack_frames(&self) -> Vec<Value> {
+        let Some(program) = &self.program else {
+            return Vec::new();
+        };
+        let pc = self.cpu.pc;
+        // Name the frame after the closest label before the PC, which is usually the function
+        let name = program
+            .labels
+            .iter()
+            .filter(|(_, &address)| address <= pc)
+            .max_by_key(|(_, &address)| address)
+            .map_or("main".to_string(), |(name, _)| name.clone());
+        let line = program.line_at(pc).unwrap_or(1);
+        let file_name = Path::new(&self.source_path)
+            .file_name()
+            .map_or(self.source_path.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        vec![json!({
+            "id": FRAME_ID,
+            "name": name,
+            "line": self.client_line(line),
+            "column": 1,
+            "source": { "name": file_name, "path": self.source_path },
+            "instructionPointerReference": pc.to_string(),
+        })]
+    }
+
+    fn variables(&self, reference: Option<i64>) -> Vec<Value> {
+        match reference {
+            Some(REGISTERS_REFERENCE) => {
+                let mut variables: Vec<Value> = (0..16)
+                    .map(|reg| {
+                        let location = Location::Register(reg);
+                        json!({
+                            "name": location.to_string(),
+                            "value": self.cpu.format_value(location.read(&self.cpu)),
+                            "variablesReference": 0,
+                        })
+                    })
+                    .collect();
+                for location in [Location::Pc, Location::Sp] {
+                    variables.push(json!({
+                        "name": location.to_string(),
+                        "value": location.read(&self.cpu).to_string(),
+                        "variablesReference": 0,
+                    }));
+                }
+                variables
+            }
+            Some(FLAGS_REFERENCE) => FLAG_NAMES
+                .iter()
+                .map(|(name, flag)| {
+                    json!({
+                        "name": name,
+                        "value": (self.cpu.get_flag(*flag) as u8).to_string(),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<String, String> {
+        let name = args["name"].as_str().unwrap_or("");
+        let value = args["value"].as_str().unwrap_or("").trim();
+        match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => {
+                let location =
+                    Location::parse(name).ok_or(format!("unknown register \"{}\"", name))?;
+                let value = parse_number(value).ok_or(format!("\"{}\" is not a number", value))?;
+                self.write_location(location, value)?;
+                Ok(match location {
+                    Location::Register(_) => self.cpu.format_value(value),
+                    _ => value.to_string(),
+                })
+            }
+            Some(FLAGS_REFERENCE) => {
+                let (_, flag) = FLAG_NAMES
+                    .iter()
+                    .find(|(flag_name, _)| *flag_name == name)
+                    .ok_or(format!("unknown flag \"{}\"", name))?;
+                let set = match value {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => return Err("a flag can only be 0 or 1".to_string()),
+                };
+                self.cpu.set_flag(*flag, set);
+                Ok((set as u8).to_string())
+            }
+            _ => Err("unknown scope".to_string()),
+        }
+    }
+
+    fn write_location(&mut self, location: Location, value: u16) -> Result<(), String> {
+        location
+            .write(&mut self.cpu, value)
+            .map_err(|fault| fault.to_string())?;
+        if location == Location::Pc {
+            self.exit_code = 0; // The program can run again
+        }
+        Ok(())
+    }
+
+    /// Evaluates a debug console or hover expression: a location, or `input <numbers>` for IN.
+    fn evaluate(&mut self, expression: &str) -> Result<String, String> {
+        let expression = expression.trim();
+        if let Some(numbers) = expression.strip_prefix("input") {
+            let mut queued = 0;
+            for token in numbers.split_whitespace() {
+                let value = parse_number(token).ok_or(format!("not a number: \"{}\"", token))?;
+                self.cpu.input.push_back(value);
+                queued += 1;
+            }
+            return Ok(format!("{} number(s) queued for IN", queued));
+        }
+        match Location::parse(expression) {
+            Some(Location::Flags) => Ok(format_flags(&self.cpu)),
+            Some(location) => Ok(self.cpu.format_value(location.read(&self.cpu))),
+            None => Err(format!(
+                "unknown expression \"{}\", expected a register, [address], pc, sp, flags or input <numbers>",
+                expression
+            )),
+        }
+    }
+
+    /// Sends the lines printed by PRINT since the last call.
+    fn flush_output(&mut self) -> io::Result<()> {
+        let lines = self
+            .cpu
+            .output
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default();
+        for line in lines {
+            self.output("stdout", &format!("{}\n", line))?;
+        }
+        Ok(())
+    }
+
+    /// Checks for a request that interrupts the program without waiting, keeping other
+    /// requests for later. A request that ends the session is kept too, so `run` answers it next.
+    fn interrupted(&mut self) -> io::Result<Option<Interrupt>> {
+        loop {
+            match self.messages.try_recv() {
+                Ok(request) if request["command"] == "pause" => {
+                    self.respond(&request, json!({}))?;
+                    return Ok(Some(Interrupt::Pause));
+                }
+                Ok(request)
+                    if request["command"] == "disconnect" || request["command"] == "terminate" =>
+                {
+                    self.deferred.push_front(request);
+                    return Ok(Some(Interrupt::End));
+                }
+                Ok(request) => self.deferred.push_back(request),
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => return Ok(Some(Interrupt::End)),
+            }
+        }
+    }
+
+    /// Runs the program as far as `resume` asks, then reports why it stopped.
+    fn resume(&mut self, resume: Resume) -> io::Result<()> {
+        let start_sp = self.cpu.sp;
+        let word = self
+            .cpu
+            .memory
+            .get(self.cpu.pc as usize)
+            .copied()
+            .unwrap_or(0);
+        let after_call = (word >> 12 == EXT_OPCODE && word & 0xFF == EXT_CALL)
+            .then(|| self.cpu.pc + instruction_length(word));
+
+        let mut executed = 0;
+        loop {
+            let result = self.cpu.step();
+            executed += 1;
+            self.flush_output()?;
+            match result {
+                StepResult::Executed => {
+                    let done = match resume {
+                        Resume::Continue => false,
+                        Resume::StepIn => true,
+                        Resume::Next => after_call.is_none_or(|address| {
+                            self.cpu.pc == address && self.cpu.sp == start_sp
+                        }),
+                        Resume::StepOut => self.cpu.sp > start_sp,
+                    };
+                    if done {
+                        return self.stopped("step", None);
+                    }
+                }
+                StepResult::Breakpoint(_) => return self.stopped("breakpoint", None),
+                StepResult::Halted => {
+                    self.event("exited", json!({ "exitCode": self.exit_code }))?;
+                    return self.event("terminated", json!({}));
+                }
+                StepResult::Fault(error) => {
+                    self.exit_code = 4; // The same exit code as a fault outside the debugger
+                    self.output("stderr", &format!("{}\n", error))?;
+                    return self.stopped("exception", Some(&error.fault.to_string()));
+                }
+                StepResult::WaitingForInput => {
+                    self.output(
+                        "console",
+                        "The program is waiting for input, type `input <number>` in the debug console.\n",
+                    )?;
+                    return self.stopped("pause", Some("Waiting for input"));
+                }
+            }
+            if executed % PAUSE_INTERVAL == 0 {
+                match self.interrupted()? {
+                    Some(Interrupt::Pause) => return self.stopped("pause", None),
+                    Some(Interrupt::End) => return Ok(()),
+                    None => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::*;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+// Instructions `continue` runs before giving control back, in case the program never stops
+const CONTINUE_LIMIT: usize = 1_000_000;
+
+const HELP: &str = "\
+break <line>          set a breakpoint on a source line (also b)
+break *<address>      set a breakpoint on an address
+break <label>         set a breakpoint on a label
+delete [<breakpoint>] remove a breakpoint, or all of them
+watch <location>      stop when a location changes
+unwatch [<location>]  remove a watchpoint, or all of them
+step [<count>]        run one or more instructions (also s)
+continue              run until something stops the program (also c)
+input <numbers...>    queue numbers for IN to read
+print <location>      show a location (also p)
+set <location> <value> change a location
+regs                  show every register and flag
+mem <address> [<count>] show memory
+list                  show the source around the PC (also l)
+reset                 reload the program, keeping breakpoints and watchpoints
+quit                  leave the debugger (also q)
+
+Locations are registers (ax to px), memory cells ([200]), pc, sp and flags.
+Numbers can be decimal or hexadecimal (0x...).";
+
+/// Something the debugger can read, change and watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Register(u16),
+    Memory(u16),
+    Pc,
+    Sp,
+    Flags,
+}
+
+impl Location {
+    pub fn parse(token: &str) -> Option<Location> {
+        let token = token.to_lowercase();
+        match token.as_str() {
+            "pc" => Some(Location::Pc),
+            "sp" => Some(Location::Sp),
+            "flags" => Some(Location::Flags),
+            _ => {
+                if let Some(address) = token.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+                    parse_number(address)
+                        .filter(|&address| (address as usize) < MEMORY_SIZE)
+                        .map(Location::Memory)
+                } else if token.len() == 2 && token.ends_with('x') {
+                    letter_to_integer(token.chars().next()?)
+                        .filter(|&reg| reg < 16)
+                        .map(|reg| Location::Register(reg.into()))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn read(&self, cpu: &CPU) -> u16 {
+        match self {
+            Location::Register(reg) => cpu.registers[*reg as usize],
+            Location::Memory(address) => cpu.memory[*address as usize],
+            Location::Pc => cpu.pc,
+            Location::Sp => cpu.sp,
+            Location::Flags => cpu.flags,
+        }
+    }
+
+    /// Changes the value at the location, unless it's an SP outside of memory. Moving the PC
+    /// lets a halted or faulted program run again.
+    pub fn write(&self, cpu: &mut CPU, value: u16) -> Result<(), Fault> {
+        match self {
+            Location::Register(reg) => cpu.registers[*reg as usize] = value,
+            Location::Memory(address) => cpu.memory[*address as usize] = value,
+            Location::Pc => cpu.set_pc(value),
+            Location::Sp => cpu.set_sp(value)?,
+            Location::Flags => cpu.flags = value,
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Register(reg) => {
+                write!(f, "{}x", integer_to_letter(*reg as usize).unwrap_or('?'))
+            }
+            Location::Memory(address) => write!(f, "[{}]", address),
+            Location::Pc => write!(f, "pc"),
+            Location::Sp => write!(f, "sp"),
+            Location::Flags => write!(f, "flags"),
+        }
+    }
+}
+
+/// Parses a number written like in the assembly, such as `42`, `0xff` or `-0b101`.
+/// Negative numbers become their two's complement.
+pub fn parse_number(token: &str) -> Option<u16> {
+    let value = match token.strip_prefix('-') {
+        Some(digits) => -parse_literal(digits).ok()?,
+        None => parse_literal(token).ok()?,
+    };
+    (-32768..=65535).contains(&value).then_some(value as u16)
+}
+
+/// Formats the flags register as its four flags.
+pub fn format_flags(cpu: &CPU) -> String {
+    format!(
+        "Z={} C={} N={} V={}",
+        cpu.get_flag(FLAG_ZERO) as u8,
+        cpu.get_flag(FLAG_CARRY) as u8,
+        cpu.get_flag(FLAG_NEGATIVE) as u8,
+        cpu.get_flag(FLAG_OVERFLOW) as u8
+    )
+}
+
+/// An interactive, gdb-like debugger for an assembly program.
+pub struct Debugger {
+    cpu: CPU,
+    program: Program,
+    source: Vec<String>,
+    arithmetic: ArithmeticMode,
+    watchpoints: Vec<(Location, u16)>, // Location and the last value seen there
+}
+
+impl Debugger {
+    pub fn new(path: &str, source: String, arithmetic: ArithmeticMode) -> Result<Debugger, Error> {
+        let program = parse_program(path, source.clone())?;
+        let mut debugger = Debugger {
+            cpu: CPU::new(),
+            program,
+            source: source.lines().map(|line| line.to_string()).collect(),
+            arithmetic,
+            watchpoints: Vec::new(),
+        };
+        debugger.reset();
+        Ok(debugger)
+    }
+
+    /// Reloads the program into a fresh CPU, keeping breakpoints and watchpoints.
+    fn reset(&mut self) {
+        self.cpu.reset(&self.program.instructions, self.arithmetic);
+        for (location, value) in &mut self.watchpoints {
+            *value = location.read(&self.cpu);
+        }
+    }
+
+    /// Reads commands from stdin until `quit` or the end of input.
+    pub fn run(&mut self) {
+        println!("Type `help` for a list of commands.");
+        self.show_position();
+        let stdin = io::stdin();
+        loop {
+            print!("{}", "(alice) ".color(Colors::BlueFg));
+            if io::stdout().flush().is_err() {
+                return;
+            }
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            if !self.command(&tokens) {
+                return;
+            }
+        }
+    }
+
+    /// Runs one command, returning false if the debugger should quit.
+    fn command(&mut self, tokens: &[&str]) -> bool {
+        match tokens[0] {
+            "break" | "b" => self.add_breakpoint(tokens.get(1).copied()),
+            "delete" | "d" => self.delete_breakpoint(tokens.get(1).copied()),
+            "watch" => match tokens.get(1).and_then(|token| Location::parse(token)) {
+                Some(location) => {
+                    self.watchpoints.push((location, location.read(&self.cpu)));
+                    println!("Watching {}.", location);
+                }
+                None => println!("Expected a location to watch."),
+            },
+            "unwatch" => match tokens.get(1) {
+                Some(token) => match Location::parse(token) {
+                    Some(location) => self.watchpoints.retain(|(watched, _)| *watched != location),
+                    None => println!("Unknown location \"{}\".", token),
+                },
+                None => self.watchpoints.clear(),
+            },
+            "step" | "s" => {
+                let count = tokens
+                    .get(1)
+                    .and_then(|token| token.parse().ok())
+                    .unwrap_or(1);
+                self.resume(count);
+            }
+            "continue" | "c" => self.resume(CONTINUE_LIMIT),
+            "input" => {
+                for token in &tokens[1..] {
+                    match parse_number(token) {
+                        Some(value) => self.cpu.input.push_back(value),
+                        None => println!("Not a number: \"{}\"", token),
+                    }
+                }
+            }
+            "print" | "p" => match tokens.get(1).and_then(|token| Location::parse(token)) {
+                Some(Location::Flags) => println!("flags = {}", format_flags(&self.cpu)),
+                Some(location) => {
+                    let value = location.read(&self.cpu);
+                    println!(
+                        "{} = {} ({:#06x})",
+                        location,
+                        self.cpu.format_value(value),
+                        value
+                    );
+                }
+                None => println!("Expected a location to print."),
+            },
+            "set" => {
+                let location = tokens.get(1).and_then(|token| Location::parse(token));
+                let value = tokens.get(2).and_then(|token| parse_number(token));
+                match (location, value) {
+                    (Some(location), Some(value)) => match location.write(&mut self.cpu, value) {
+                        Ok(()) => {
+                            // A change made here isn't one the program made, so it isn't reported
+                            for (watched, last) in &mut self.watchpoints {
+                                if *watched == location {
+                                    *last = location.read(&self.cpu);
+                                }
+                            }
+                        }
+                        Err(fault) => err_print(fault.to_string()),
+                    },
+                    _ => println!("Usage: set <location> <value>"),
+                }
+            }
+            "regs" | "info" => self.show_registers(),
+            "mem" | "x" => {
+                let address = tokens.get(1).and_then(|token| parse_number(token));
+                let count = tokens
+                    .get(2)
+                    .and_then(|token| parse_number(token))
+                    .unwrap_or(8);
+                match address {
+                    Some(address) => self.show_memory(address, count),
+                    None => println!("Usage: mem <address> [<count>]"),
+                }
+            }
+            "list" | "l" => self.show_source(),
+            "reset" | "run" => {
+                self.reset();
+                self.show_position();
+            }
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return false,
+            command => println!("Unknown command \"{}\", type `help` for a list.", command),
+        }
+        true
+    }
+
+    /// Finds the address a breakpoint refers to: `*address`, a source line or a label.
+    fn breakpoint_address(&self, token: &str) -> Option<u16> {
+        if let Some(address) = token.strip_prefix('*') {
+            parse_number(address)
+        } else if let Ok(line) = token.parse::<usize>() {
+            self.program.address_of_line(line)
+        } else {
+            self.program.labels.get(token).copied()
+        }
+    }
+
+    fn add_breakpoint(&mut self, token: Option<&str>) {
+        match token.and_then(|token| self.breakpoint_address(token)) {
+            Some(address) => {
+                self.cpu.breakpoints.insert(address);
+                match self.program.line_at(address) {
+                    Some(line) => println!("Breakpoint at address {} (line {}).", address, line),
+                    None => println!("Breakpoint at address {}.", address),
+                }
+            }
+            None => println!("Expected a source line, *address or label with code."),
+        }
+    }
+
+    fn delete_breakpoint(&mut self, token: Option<&str>) {
+        match token {
+            Some(token) => match self.breakpoint_address(token) {
+                Some(address) if self.cpu.breakpoints.remove(&address) => {
+                    println!("Deleted breakpoint at address {}.", address)
+                }
+                _ => println!("No breakpoint at \"{}\".", token),
+            },
+            None => self.cpu.breakpoints.clear(),
+        }
+    }
+
+    /// Steps up to `count` instructions, stopping on anything other than a plain step.
+    fn resume(&mut self, count: usize) {
+        let mut executed = 0;
+        while executed < count {
+            let result = self.cpu.step();
+            executed += 1;
+            if self.check_watchpoints() {
+                break;
+            }
+            match result {
+                StepResult::Executed => continue,
+                StepResult::Breakpoint(address) => println!("Breakpoint at address {}.", address),
+                StepResult::Halted => {
+                    println!("{}", "The program has halted.".color(Colors::YellowFg));
+                    return;
+                }
+                StepResult::Fault(error) => {
+                    err_print(error.to_string());
+                    return;
+                }
+                StepResult::WaitingForInput => {
+                    println!("The program is waiting for input, use `input <number>`.");
+                }
+            }
+            break;
+        }
+        if count == CONTINUE_LIMIT && executed == count {
+            println!("Stopped after {} instructions.", CONTINUE_LIMIT);
+        }
+        self.show_position();
+    }
+
+    /// Reports every watchpoint whose value changed, returning true if any did.
+    fn check_watchpoints(&mut self) -> bool {
+        let mut changed = false;
+        for (location, last) in &mut self.watchpoints {
+            let value = location.read(&self.cpu);
+            if value != *last {
+                println!("Watchpoint {}: {} -> {}", location, last, value);
+                *last = value;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn show_position(&self) {
+        let pc = self.cpu.pc;
+        let word = self.cpu.memory.get(pc as usize).copied().unwrap_or(0);
+        match self.program.line_at(pc) {
+            Some(line) => println!(
+                "{} {:>3}: {:#06x}  line {}: {}",
+                "=>".color(Colors::GreenFg),
+                pc,
+                word,
+                line,
+                self.source.get(line - 1).map_or("", |text| text.trim())
+            ),
+            None => println!("{} {:>3}: {:#06x}", "=>".color(Colors::GreenFg), pc, word),
+        }
+    }
+
+    fn show_registers(&self) {
+        for (i, &value) in self.cpu.registers.iter().enumerate() {
+            println!(
+                "R{:<2} {}x: {}",
+                i,
+                integer_to_letter(i).unwrap_or('?'),
+                self.cpu.format_value(value).color(Colors::CyanFg)
+            );
+        }
+        println!("PC: {}", self.cpu.pc.to_string().color(Colors::CyanFg));
+        println!("SP: {}", self.cpu.sp.to_string().color(Colors::CyanFg));
+        println!("FLAGS: {}", format_flags(&self.cpu).color(Colors::CyanFg));
+    }
+
+    fn show_memory(&self, address: u16, count: u16) {
+        let start = address as usize;
+        let end = (start + count as usize).min(MEMORY_SIZE);
+        for (i, chunk) in self.cpu.memory[start.min(end)..end].chunks(8).enumerate() {
+            let words: Vec<String> = chunk.iter().map(|word| format!("{:#06x}", word)).collect();
+            println!("{:>3}: {}", start + i * 8, words.join(" "));
+        }
+    }
+
+    fn show_source(&self) {
+        let current = self.program.line_at(self.cpu.pc).unwrap_or(1);
+        let first = current.saturating_sub(4).max(1);
+        let last = (current + 4).min(self.source.len());
+        for line in first..=last {
+            let has_breakpoint = self
+                .program
+                .source_map
+                .iter()
+                .any(|(address, l)| *l == line && self.cpu.breakpoints.contains(address));
+            let marker = match (line == current, has_breakpoint) {
+                (true, _) => "=>",
+                (false, true) => " b",
+                (false, false) => "  ",
+            };
+            println!("{} {:>4} | {}", marker, line, self.source[line - 1]);
+        }
+    }
+}
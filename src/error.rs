@@ -0,0 +1,446 @@
+use crate::*;
+use std::fmt;
+use std::io;
+
+/// The reasons the CPU can stop on an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    NegativeResult(&'static str), // Instruction name
+    Overflow(&'static str),
+    DivideByZero,
+    MemoryOutOfBounds(u16), // Address
+    StackOverflow,
+    StackUnderflow,
+    StackPointerOutOfBounds(u16), // SP, which was set past the end of memory
+    UnknownInstruction,
+    NoInput, // IN ran out of input
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::NegativeResult(name) => write!(f, "{} will result in a negative number", name),
+            Fault::Overflow(name) => write!(f, "{} will overflow", name),
+            Fault::DivideByZero => write!(f, "dividing by zero is not allowed"),
+            Fault::MemoryOutOfBounds(address) => {
+                write!(f, "memory access out of bounds at address {}", address)
+            }
+            Fault::StackOverflow => write!(f, "stack overflow"),
+            Fault::StackUnderflow => write!(f, "stack underflow, the stack is empty"),
+            Fault::StackPointerOutOfBounds(sp) => {
+                write!(f, "stack pointer {} is past the end of memory", sp)
+            }
+            Fault::UnknownInstruction => write!(f, "unknown instruction"),
+            Fault::NoInput => write!(f, "no input left to read"),
+        }
+    }
+}
+
+/// An error that stopped the CPU, along with where it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuError {
+    pub fault: Fault,
+    pub pc: u16,          // Address of the offending instruction
+    pub instruction: u16, // First word of the offending instruction
+    pub operand: u16,     // Operand word, 0 for one-word instructions
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at PC {} (instruction {:#06x}",
+            self.fault, self.pc, self.instruction
+        )?;
+        if instruction_length(self.instruction) == 2 {
+            write!(f, " {:#06x}", self.operand)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The kinds of mistakes the assembler can find in a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownInstruction(String),
+    InvalidLabel(String),
+    DuplicateLabel(String),
+    UnknownLabel(String),
+    MissingOperand,
+    AddressOutOfRange(i32),
+    ValueOutOfRange(String, i32, i32), // Value, minimum, maximum
+    InvalidNumber(String),
+    InvalidMemoryOperand,
+    EndWithoutFunction,
+    NestedFunction,
+    MissingEnd(String),     // Function name
+    UnlinkedSymbol(String), // A `.extern` symbol in a program that isn't linked
+    NestedMacro,
+    EndmWithoutMacro,
+    MissingEndm(String), // Macro name
+    DuplicateMacro(String),
+    WrongArgumentCount(String, usize, usize), // Macro name, parameters, arguments
+    RecursiveMacro(String),
+    IncludeNotFound(String),
+    IncludeCycle(String),
+    IncludeFailed(String), // Why the file couldn't be read
+    InvalidExpression(String),
+    ExpressionOverflow(String),
+    DivisionByZero,
+    LabelArithmetic(String), // An expression that does more to a label than add or subtract
+    ImportedLabelDistance(String), // An expression that subtracts a label from another file
+    UnexpectedOperand(String),
+    InvalidString(String),
+    UnknownEscape(String),
+    InvalidCharacter(String),
+    WrongOperandCount(String, u16, u16), // Instruction, operands it takes, operands given
+    ExpectedRegister(String),
+    ExpectedNumber(String), // A register where a number or address goes
+    UnexpectedMemoryOperand(String),
+    LabelInByte(String),                // A label's address given to `.byte`
+    LabelInImmediate(String, i32, i32), // A label's address where it doesn't fit, and the range
+    ProgramTooLarge(usize),             // Size of the program in words
+    UnexpectedCharacter(char),
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownInstruction(name) => write!(f, "unknown instruction \"{}\"", name),
+            AsmErrorKind::InvalidLabel(name) => write!(f, "invalid label name \"{}\"", name),
+            AsmErrorKind::DuplicateLabel(name) => write!(f, "duplicate label \"{}\"", name),
+            AsmErrorKind::UnknownLabel(name) => {
+                write!(f, "unknown label or constant \"{}\"", name)
+            }
+            AsmErrorKind::MissingOperand => write!(f, "missing operand"),
+            AsmErrorKind::AddressOutOfRange(address) => {
+                write!(f, "address {} is outside of memory", address)
+            }
+            AsmErrorKind::ValueOutOfRange(value, min, max) => {
+                write!(f, "{} must be a number from {} to {}", value, min, max)
+            }
+            AsmErrorKind::InvalidNumber(token) => write!(f, "\"{}\" is not a valid number", token),
+            AsmErrorKind::InvalidMemoryOperand => write!(
+                f,
+                "expected a memory operand like [address], [reg] or [reg+offset]"
+            ),
+            AsmErrorKind::EndWithoutFunction => write!(f, ".end without a corresponding function"),
+            AsmErrorKind::NestedFunction => {
+                write!(f, "nested function definitions are not allowed")
+            }
+            AsmErrorKind::MissingEnd(name) => write!(f, "function .{} is missing its .end", name),
+            AsmErrorKind::UnlinkedSymbol(name) => write!(
+                f,
+                "\"{}\" is defined in another file, use `link` to assemble them together",
+                name
+            ),
+            AsmErrorKind::NestedMacro => write!(f, "macros can't be defined inside a macro"),
+            AsmErrorKind::EndmWithoutMacro => write!(f, ".endm without a corresponding .macro"),
+            AsmErrorKind::MissingEndm(name) => write!(f, "macro {} is missing its .endm", name),
+            AsmErrorKind::DuplicateMacro(name) => write!(f, "duplicate macro \"{}\"", name),
+            AsmErrorKind::WrongArgumentCount(name, params, args) => write!(
+                f,
+                "macro {} takes {} arguments, but was given {}",
+                name, params, args
+            ),
+            AsmErrorKind::RecursiveMacro(name) => write!(
+                f,
+                "macro {} expands more than {} levels deep, it probably uses itself",
+                name, MAX_MACRO_DEPTH
+            ),
+            AsmErrorKind::IncludeNotFound(name) => write!(
+                f,
+                "could not find \"{}\" next to this file or in the include paths",
+                name
+            ),
+            AsmErrorKind::IncludeCycle(name) => {
+                write!(f, "\"{}\" ends up including itself", name)
+            }
+            AsmErrorKind::IncludeFailed(error) => {
+                write!(f, "could not read the included file: {}", error)
+            }
+            AsmErrorKind::InvalidExpression(text) => {
+                write!(f, "\"{}\" is not a valid expression", text)
+            }
+            AsmErrorKind::ExpressionOverflow(text) => {
+                write!(f, "\"{}\" doesn't fit in 32 bits", text)
+            }
+            AsmErrorKind::DivisionByZero => write!(f, "division by zero in an expression"),
+            AsmErrorKind::LabelArithmetic(text) => write!(
+                f,
+                "\"{}\" can only add a number to a label, or subtract one label from another",
+                text
+            ),
+            AsmErrorKind::ImportedLabelDistance(text) => write!(
+                f,
+                "\"{}\" subtracts a label from another file, which is only known once it's linked",
+                text
+            ),
+            AsmErrorKind::UnexpectedOperand(operand) => {
+                write!(f, "unexpected operand \"{}\"", operand)
+            }
+            AsmErrorKind::InvalidString(text) => {
+                write!(
+                    f,
+                    "{} is not a valid string, it should be in double quotes",
+                    text
+                )
+            }
+            AsmErrorKind::UnknownEscape(escape) => {
+                write!(f, "unknown escape sequence \"{}\"", escape)
+            }
+            AsmErrorKind::InvalidCharacter(text) => write!(
+                f,
+                "{} is not a valid character, it should be one ASCII character or escape in single quotes",
+                text
+            ),
+            AsmErrorKind::WrongOperandCount(name, expected, given) => write!(
+                f,
+                "{} takes {} operand{}, but was given {}",
+                name,
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                given
+            ),
+            AsmErrorKind::ExpectedRegister(token) => {
+                write!(f, "expected a register, but found \"{}\"", token)
+            }
+            AsmErrorKind::ExpectedNumber(token) => {
+                write!(f, "expected a number, but found the register \"{}\"", token)
+            }
+            AsmErrorKind::UnexpectedMemoryOperand(token) => write!(
+                f,
+                "memory operand \"{}\" can only be used with LOAD and STORE",
+                token
+            ),
+            AsmErrorKind::LabelInByte(value) => write!(
+                f,
+                "\"{}\" is the address of a label, which only fits in a .word",
+                value
+            ),
+            AsmErrorKind::LabelInImmediate(value, min, max) => write!(
+                f,
+                "\"{}\" is the address of a label, which can't be used where values go from {} to {}",
+                value, min, max
+            ),
+            AsmErrorKind::ProgramTooLarge(size) => write!(
+                f,
+                "the program is {} words, but memory only holds {}",
+                size, MEMORY_SIZE
+            ),
+            AsmErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected character {:?}", c),
+        }
+    }
+}
+
+/// A macro that a line was expanded from, and the line of its definition the line came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroFrame {
+    pub name: String,
+    pub line: usize,
+    pub file: String, // Empty for the file being assembled
+    pub text: String, // The line of the definition itself
+}
+
+/// An error found while assembling, along with the source line it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub kind: AsmErrorKind,
+    pub line: usize,               // Line number, starting at 1
+    pub column: usize,             // Column of the mistake from 1, or 0 for the whole line
+    pub text: String,              // The source line itself
+    pub file: String,              // The file the line is in, if known
+    pub macros: Box<[MacroFrame]>, // Macros the line was expanded from, innermost first
+}
+
+impl AsmError {
+    pub fn new(kind: AsmErrorKind, line: usize) -> AsmError {
+        AsmError {
+            kind,
+            line,
+            column: 0,
+            text: String::new(),
+            file: String::new(),
+            macros: Box::default(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on line {}", self.kind, self.line)?;
+        if !self.file.is_empty() {
+            write!(f, " of {}", self.file)?;
+        }
+        if !self.text.is_empty() {
+            let number = format!("{:>4}", self.line);
+            write!(f, "\n{} | {}", number, self.text.trim_end())?;
+            // In a macro, the column could be on this line or the macro's, so it isn't shown
+            if self.column > 0 && self.macros.is_empty() {
+                let indent: String = self
+                    .text
+                    .chars()
+                    .take(self.column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(f, "\n{} | {}^", " ".repeat(number.len()), indent)?;
+            }
+        }
+        for frame in &self.macros {
+            write!(f, "\nin macro {} on line {}", frame.name, frame.line)?;
+            if !frame.file.is_empty() {
+                write!(f, " of {}", frame.file)?;
+            }
+            if !frame.text.is_empty() {
+                write!(f, "\n{:>4} | {}", frame.line, frame.text.trim_end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The ways a binary file can be malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryError {
+    NotABinary, // Missing the magic bytes
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingBytes(usize),           // Number of extra bytes
+    UnknownSection(u16),            // Section kind
+    SectionOutOfMemory(u16, usize), // Address, length
+    OverlappingSections(u16),       // First address that is used twice
+    EntryOutOfRange(u16),
+    InvalidRecord(usize), // Line of a HEX or S-record image
+    BadChecksum(usize),
+    MissingEndRecord,
+    IncompleteWord(u16), // Address of a word with only one of its bytes
+    InvalidSymbol,       // A symbol in an object file is not valid
+    UnknownRelocation(u16),
+    RelocationOutOfRange(u16), // Address of the relocation
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinaryError::NotABinary => write!(f, "not an alice binary"),
+            BinaryError::UnsupportedVersion(version) => write!(
+                f,
+                "the binary is for ISA version {}, but this is version {}",
+                version, ISA_VERSION
+            ),
+            BinaryError::Truncated => write!(f, "the binary ends in the middle of a section"),
+            BinaryError::TrailingBytes(count) => {
+                write!(f, "{} extra bytes after the last section", count)
+            }
+            BinaryError::UnknownSection(kind) => write!(f, "unknown section kind {}", kind),
+            BinaryError::SectionOutOfMemory(address, length) => write!(
+                f,
+                "a section of {} words at address {} doesn't fit in memory",
+                length, address
+            ),
+            BinaryError::OverlappingSections(address) => {
+                write!(f, "two sections overlap at address {}", address)
+            }
+            BinaryError::EntryOutOfRange(address) => {
+                write!(f, "the entry point {} is outside of the code", address)
+            }
+            BinaryError::InvalidRecord(line) => write!(f, "invalid record on line {}", line),
+            BinaryError::BadChecksum(line) => write!(f, "wrong checksum on line {}", line),
+            BinaryError::MissingEndRecord => write!(f, "the image has no end record"),
+            BinaryError::IncompleteWord(address) => {
+                write!(f, "only one byte of the word at address {} is set", address)
+            }
+            BinaryError::InvalidSymbol => write!(f, "invalid symbol in the object file"),
+            BinaryError::UnknownRelocation(kind) => write!(f, "unknown relocation kind {}", kind),
+            BinaryError::RelocationOutOfRange(address) => {
+                write!(
+                    f,
+                    "relocation at address {} is outside of the code",
+                    address
+                )
+            }
+        }
+    }
+}
+
+/// The ways linking objects together can fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    DuplicateSymbol(String, String, String), // Symbol, and the two files exporting it
+    UndefinedSymbol(String, String),         // Symbol, and the file importing it
+    ProgramTooLarge(usize),                  // Size of the linked program in words
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::DuplicateSymbol(name, first, second) => write!(
+                f,
+                "symbol \"{}\" is exported by both {} and {}",
+                name, first, second
+            ),
+            LinkError::UndefinedSymbol(name, file) => write!(
+                f,
+                "symbol \"{}\" used by {} is not exported by any file",
+                name, file
+            ),
+            LinkError::ProgramTooLarge(size) => write!(
+                f,
+                "the linked program is {} words, but memory only holds {}",
+                size, MEMORY_SIZE
+            ),
+        }
+    }
+}
+
+/// Any error that stops alice, used to pick the exit code.
+#[derive(Debug)]
+pub enum Error {
+    Io(String, io::Error), // What was being done, and the error
+    Binary(String, BinaryError),
+    Asm(Box<AsmError>), // Boxed, since it carries the source lines
+    Cpu(CpuError),
+    Link(LinkError),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(..) => 1,
+            Error::Asm(_) => 3,
+            Error::Cpu(_) => 4,
+            Error::Binary(..) => 5,
+            Error::Link(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(context, error) => write!(f, "{}: {}", context, error),
+            Error::Binary(context, error) => write!(f, "{}: {}", context, error),
+            Error::Asm(error) => write!(f, "{}", error),
+            Error::Cpu(error) => write!(f, "{}", error),
+            Error::Link(error) => write!(f, "Error linking: {}", error),
+        }
+    }
+}
+
+impl From<AsmError> for Error {
+    fn from(error: AsmError) -> Error {
+        Error::Asm(Box::new(error))
+    }
+}
+
+impl From<LinkError> for Error {
+    fn from(error: LinkError) -> Error {
+        Error::Link(error)
+    }
+}
+
+impl From<CpuError> for Error {
+    fn from(error: CpuError) -> Error {
+        Error::Cpu(error)
+    }
+}
@@ -0,0 +1,436 @@
+use crate::*;
+use std::collections::HashMap;
+
+/// The value of an expression. Values that depend on where a label is remember the label, so
+/// the linker can move them along with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprValue {
+    pub value: i32,
+    pub label: Option<String>, // The label the value is relative to
+}
+
+impl ExprValue {
+    fn constant(value: i32) -> ExprValue {
+        ExprValue { value, label: None }
+    }
+}
+
+/// What the names in an expression can refer to.
+pub struct Scope<'a> {
+    pub constants: &'a HashMap<String, i32>, // From `.equ`
+    pub labels: &'a HashMap<String, u16>,
+    pub imports: &'a [String], // Labels from other files, which are only placeholders here
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// Binary operators from the loosest to the tightest binding, like in C
+const PRECEDENCE: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+const OPERATORS: [&str; 13] = [
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, AsmErrorKind> {
+    let invalid = || AsmErrorKind::InvalidExpression(text.to_string());
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_literal(&rest[..end])?));
+            rest = &rest[end..];
+        } else if c == '\'' {
+            let (value, length) = char_literal(rest)?;
+            tokens.push(Token::Number(value));
+            rest = &rest[length..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(invalid)?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a number: decimal, `0x` hexadecimal, `0o` octal or `0b` binary, with underscores
+/// allowed between the digits, like `0b1010_0101`.
+pub fn parse_literal(text: &str) -> Result<i64, AsmErrorKind> {
+    let lower = text.to_ascii_lowercase();
+    let (digits, radix) = match lower.get(..2) {
+        Some("0x") => (&lower[2..], 16),
+        Some("0o") => (&lower[2..], 8),
+        Some("0b") => (&lower[2..], 2),
+        _ => (lower.as_str(), 10),
+    };
+    let valid = digits.chars().all(|c| c == '_' || c.is_digit(radix))
+        && digits.starts_with(|c: char| c.is_digit(radix))
+        && !digits.ends_with('_');
+    if !valid {
+        return Err(AsmErrorKind::InvalidNumber(text.to_string()));
+    }
+    // With only valid digits, this can only fail by being too big
+    i64::from_str_radix(&digits.replace('_', ""), radix)
+        .map_err(|_| AsmErrorKind::ExpressionOverflow(text.to_string()))
+}
+
+/// Reads the rest of an escape sequence in a string or character literal, after its backslash.
+pub fn unescape(chars: &mut std::str::Chars) -> Result<u8, AsmErrorKind> {
+    let Some(escape) = chars.next() else {
+        return Err(AsmErrorKind::UnknownEscape("\\".to_string()));
+    };
+    Ok(match escape {
+        'n' => b'\n',
+        't' => b'\t',
+        'r' => b'\r',
+        '0' => 0,
+        '\\' => b'\\',
+        '"' => b'"',
+        '\'' => b'\'',
+        'x' => {
+            let digits: String = chars.by_ref().take(2).collect();
+            if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(AsmErrorKind::UnknownEscape(format!("\\x{}", digits)));
+            }
+            u8::from_str_radix(&digits, 16).unwrap_or(0)
+        }
+        _ => return Err(AsmErrorKind::UnknownEscape(format!("\\{}", escape))),
+    })
+}
+
+/// Reads a character literal like `'a'` or `'\n'` from the start of the text, giving its value
+/// and its length. Only ASCII characters and escapes fit in one.
+fn char_literal(text: &str) -> Result<(i64, usize), AsmErrorKind> {
+    let literal = match text[1..].find('\'') {
+        Some(end) => &text[..end + 2],
+        None => text,
+    };
+    let invalid = || AsmErrorKind::InvalidCharacter(literal.to_string());
+    let mut chars = text[1..].chars();
+    let value = match chars.next() {
+        Some('\\') => unescape(&mut chars)?,
+        Some(c) if c.is_ascii() && c != '\'' => c as u8,
+        _ => return Err(invalid()),
+    };
+    if chars.next() != Some('\'') {
+        return Err(invalid());
+    }
+    Ok((value as i64, text.len() - chars.as_str().len()))
+}
+
+/// Evaluates a constant expression, like `MAX-1`, `label+2` or `(1<<4)|3`. Numbers are
+/// worked out as 32 bit signed integers, and going past that is an error.
+pub fn evaluate(text: &str, scope: &Scope) -> Result<ExprValue, AsmErrorKind> {
+    let mut parser = ExprParser {
+        text,
+        tokens: tokenize(text)?,
+        position: 0,
+        scope,
+    };
+    let value = parser.binary(0)?;
+    if parser.position != parser.tokens.len() {
+        return Err(AsmErrorKind::InvalidExpression(text.to_string()));
+    }
+    Ok(value)
+}
+
+struct ExprParser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    scope: &'a Scope<'a>,
+}
+
+impl ExprParser<'_> {
+    fn invalid(&self) -> AsmErrorKind {
+        AsmErrorKind::InvalidExpression(self.text.to_string())
+    }
+
+    fn next_op(&self, ops: &[&str]) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Op(op)) if ops.contains(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    /// Parses the operators at a precedence level and everything that binds tighter.
+    fn binary(&mut self, level: usize) -> Result<ExprValue, AsmErrorKind> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.next_op(PRECEDENCE[level]) {
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            left = self.apply(op, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<ExprValue, AsmErrorKind> {
+        if let Some(op) = self.next_op(&["-", "+", "~"]) {
+            self.position += 1;
+            let value = self.unary()?;
+            if op == "+" {
+                return Ok(value);
+            }
+            if value.label.is_some() {
+                return Err(AsmErrorKind::LabelArithmetic(self.text.to_string()));
+            }
+            let result = match op {
+                "-" => -(value.value as i64),
+                _ => !(value.value as i64),
+            };
+            return self.fit(result).map(ExprValue::constant);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<ExprValue, AsmErrorKind> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Number(number)) => self.fit(number).map(ExprValue::constant),
+            Some(Token::Name(name)) => {
+                if let Some(&value) = self.scope.constants.get(&name) {
+                    Ok(ExprValue::constant(value))
+                } else if let Some(&address) = self.scope.labels.get(&name) {
+                    Ok(ExprValue {
+                        value: address as i32,
+                        label: Some(name),
+                    })
+                } else {
+                    Err(AsmErrorKind::UnknownLabel(name))
+                }
+            }
+            Some(Token::Op("(")) => {
+                let value = self.binary(0)?;
+                if self.next_op(&[")"]).is_none() {
+                    return Err(self.invalid());
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn apply(
+        &self,
+        op: &str,
+        left: ExprValue,
+        right: ExprValue,
+    ) -> Result<ExprValue, AsmErrorKind> {
+        // A label can only be moved by a constant, or subtracted from another label
+        let label = match (op, left.label, right.label) {
+            (_, None, None) => None,
+            ("+", Some(label), None) | ("+", None, Some(label)) | ("-", Some(label), None) => {
+                Some(label)
+            }
+            // The distance to a label in another file isn't known until it's linked
+            ("-", Some(a), Some(b))
+                if self.scope.imports.contains(&a) || self.scope.imports.contains(&b) =>
+            {
+                return Err(AsmErrorKind::ImportedLabelDistance(self.text.to_string()));
+            }
+            ("-", Some(_), Some(_)) => None, // The distance between two labels
+            _ => return Err(AsmErrorKind::LabelArithmetic(self.text.to_string())),
+        };
+
+        let (a, b) = (left.value as i64, right.value as i64);
+        let result = match op {
+            "+" => a + b,
+            "-" => a - b,
+            "*" => a * b,
+            "/" | "%" if b == 0 => return Err(AsmErrorKind::DivisionByZero),
+            "/" => a / b,
+            "%" => a % b,
+            "<<" | ">>" if !(0..32).contains(&b) => {
+                return Err(AsmErrorKind::ValueOutOfRange(b.to_string(), 0, 31))
+            }
+            "<<" => a << b,
+            ">>" => a >> b,
+            "&" => a & b,
+            "|" => a | b,
+            _ => a ^ b,
+        };
+        Ok(ExprValue {
+            value: self.fit(result)?,
+            label,
+        })
+    }
+
+    /// Checks that a result still fits in 32 bits.
+    fn fit(&self, value: i64) -> Result<i32, AsmErrorKind> {
+        i32::try_from(value).map_err(|_| AsmErrorKind::ExpressionOverflow(self.text.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> Result<ExprValue, AsmErrorKind> {
+        let constants = HashMap::from([("MAX".to_string(), 10)]);
+        let labels = HashMap::from([
+            ("start".to_string(), 4),
+            ("end".to_string(), 9),
+            ("far".to_string(), 0), // Imported, so its address is a placeholder
+        ]);
+        let scope = Scope {
+            constants: &constants,
+            labels: &labels,
+            imports: &["far".to_string()],
+        };
+        evaluate(text, &scope)
+    }
+
+    fn value(text: &str) -> i32 {
+        eval(text).expect("expression evaluates").value
+    }
+
+    #[test]
+    fn follows_c_precedence() {
+        assert_eq!(value("1 + 2 * 3"), 7);
+        assert_eq!(value("(1 + 2) * 3"), 9);
+        assert_eq!(value("1 << 2 + 1"), 8);
+        assert_eq!(value("6 & 3 | 8"), 10);
+        assert_eq!(value("1 | 6 ^ 3 & 5"), 7);
+        assert_eq!(value("10 - 4 - 3"), 3);
+        assert_eq!(value("-2 * -3"), 6);
+        assert_eq!(value("~0 & 0xFF"), 255);
+        assert_eq!(value("MAX % 4 + MAX / 4"), 4);
+    }
+
+    #[test]
+    fn reads_literals() {
+        assert_eq!(
+            value("0x1F + 0o17 + 0b1010_0101 + 1_000"),
+            31 + 15 + 165 + 1000
+        );
+        assert_eq!(value("'a'"), 97);
+        assert_eq!(value("'\\n' + '\\x41'"), 10 + 65);
+        assert_eq!(
+            eval("0b102"),
+            Err(AsmErrorKind::InvalidNumber("0b102".to_string()))
+        );
+        assert_eq!(
+            eval("1_"),
+            Err(AsmErrorKind::InvalidNumber("1_".to_string()))
+        );
+        assert_eq!(
+            eval("'ab'"),
+            Err(AsmErrorKind::InvalidCharacter("'ab'".to_string()))
+        );
+        assert_eq!(
+            eval("'\\q'"),
+            Err(AsmErrorKind::UnknownEscape("\\q".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_results_past_32_bits() {
+        assert_eq!(value("0x7FFFFFFF"), i32::MAX);
+        assert_eq!(value("-0x7FFFFFFF - 1"), i32::MIN);
+        for text in [
+            "0x80000000",
+            "0x7FFFFFFF + 1",
+            "65536 * 65536",
+            "99999999999999999999",
+        ] {
+            assert_eq!(
+                eval(text),
+                Err(AsmErrorKind::ExpressionOverflow(text.to_string()))
+            );
+        }
+        assert_eq!(
+            eval("1 << 32"),
+            Err(AsmErrorKind::ValueOutOfRange("32".to_string(), 0, 31))
+        );
+        assert_eq!(eval("MAX / (5 - 5)"), Err(AsmErrorKind::DivisionByZero));
+    }
+
+    #[test]
+    fn moves_labels_only_by_constants() {
+        let label = |value, name: &str| {
+            Ok(ExprValue {
+                value,
+                label: Some(name.to_string()),
+            })
+        };
+        assert_eq!(eval("start"), label(4, "start"));
+        assert_eq!(eval("start + 2"), label(6, "start"));
+        assert_eq!(eval("MAX + end"), label(19, "end"));
+        assert_eq!(eval("end - 1"), label(8, "end"));
+        assert_eq!(eval("end - start"), Ok(ExprValue::constant(5)));
+        assert_eq!(eval("(end - start) * 2"), Ok(ExprValue::constant(10)));
+        for text in [
+            "start + end",
+            "start * 2",
+            "1 - start",
+            "-start",
+            "start & 1",
+        ] {
+            assert_eq!(
+                eval(text),
+                Err(AsmErrorKind::LabelArithmetic(text.to_string()))
+            );
+        }
+        assert_eq!(
+            eval("nowhere"),
+            Err(AsmErrorKind::UnknownLabel("nowhere".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_distances_to_imported_labels() {
+        assert_eq!(
+            eval("far + 2"),
+            Ok(ExprValue {
+                value: 2,
+                label: Some("far".to_string()),
+            })
+        );
+        for text in ["far - start", "end - far", "far - far"] {
+            assert_eq!(
+                eval(text),
+                Err(AsmErrorKind::ImportedLabelDistance(text.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for text in ["1 +", "(1 + 2", "1 2", "1 $ 2", ")"] {
+            assert_eq!(
+                eval(text),
+                Err(AsmErrorKind::InvalidExpression(text.to_string()))
+            );
+        }
+    }
+}
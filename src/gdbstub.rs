@@ -0,0 +1,659 @@
+use crate::*;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+// Instructions run between checks for an interrupt from the client
+const INTERRUPT_INTERVAL: usize = 1000;
+
+// Registers as GDB numbers them: ax to px, then pc, sp and flags
+const REGISTER_COUNT: usize = 19;
+const PC_REGISTER: usize = 16;
+const SP_REGISTER: usize = 17;
+const FLAGS_REGISTER: usize = 18;
+
+// Signals used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// A stream the stub can talk to a client over.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Builds the target description, so clients know the names and sizes of the registers.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n<feature name=\"org.alice.core\">\n",
+    );
+    for reg in 0..16 {
+        xml += &format!(
+            "<reg name=\"{}x\" bitsize=\"16\" type=\"uint16\"/>\n",
+            integer_to_letter(reg).unwrap_or('?')
+        );
+    }
+    xml += "<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\n";
+    xml += "<reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\"/>\n";
+    xml += "<reg name=\"flags\" bitsize=\"16\" type=\"uint16\"/>\n";
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses the `address,length` pair used by memory and breakpoint packets.
+fn parse_address_length(text: &str) -> Option<(usize, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        usize::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+/// A GDB remote serial protocol stub for one client.
+/// Memory is shown to the client as bytes, two little-endian bytes per word,
+/// so every address the client sees, including the PC and SP, is twice the word address.
+pub struct GdbStub<C: Connection> {
+    cpu: CPU,
+    program: Vec<Instruction>,
+    arithmetic: ArithmeticMode,
+    connection: C,
+    pending: VecDeque<u8>, // Bytes received but not handled yet
+    no_ack: bool,          // Set once the client turns off acknowledgements
+    last_stop: String,     // Reply to `?`
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(program: Vec<Instruction>, arithmetic: ArithmeticMode, connection: C) -> Self {
+        let mut stub = GdbStub {
+            cpu: CPU::new(),
+            program,
+            arithmetic,
+            connection,
+            pending: VecDeque::new(),
+            no_ack: false,
+            last_stop: format!("S{:02x}", SIGTRAP),
+        };
+        stub.reset();
+        stub
+    }
+
+    /// Reloads the program into a fresh CPU, keeping breakpoints.
+    fn reset(&mut self) {
+        self.cpu.reset(&self.program, self.arithmetic);
+        self.last_stop = format!("S{:02x}", SIGTRAP);
+    }
+
+    /// Answers packets until the client detaches, kills the program or disconnects.
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'D') => {
+                    self.send_packet("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => self.handle_packet(&packet)?,
+            };
+            self.send_packet(&reply)?;
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true; // Only after the OK, which is still acknowledged
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pending.is_empty() {
+            let mut buffer = [0; 1024];
+            let read = self.connection.read(&mut buffer)?;
+            self.pending.extend(&buffer[..read]);
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    /// Reads the next packet, answering acknowledgements and interrupts on the way.
+    /// Returns None once the client disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(0x03) => {
+                    // An interrupt while the program is stopped, which just reports the stop again
+                    let reply = format!("S{:02x}", SIGINT);
+                    self.send_packet(&reply)?;
+                    continue;
+                }
+                Some(_) => continue, // Acknowledgements and noise
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in &mut checksum {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if self.no_ack {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            if expected == Some(actual) {
+                self.connection.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.connection.write_all(b"-")?; // Asks the client to send it again
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.connection, "${}#{:02x}", data, checksum)?;
+        self.connection.flush()
+    }
+
+    /// Sends text for the client to show on its console.
+    fn send_output(&mut self, text: &str) -> io::Result<()> {
+        self.send_packet(&format!("O{}", to_hex(text.as_bytes())))
+    }
+
+    /// Handles one packet and returns the reply. An empty reply means the packet isn't supported.
+    fn handle_packet(&mut self, packet: &str) -> io::Result<String> {
+        let Some(command) = packet.get(..1) else {
+            return Ok(String::new());
+        };
+        let args = &packet[1..];
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => {
+                let mut bytes = Vec::new();
+                for register in 0..REGISTER_COUNT {
+                    bytes.extend(self.read_register(register).to_le_bytes());
+                }
+                to_hex(&bytes)
+            }
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() == REGISTER_COUNT * 2 => {
+                    let values: Vec<u16> = bytes
+                        .chunks_exact(2)
+                        .map(|value| u16::from_le_bytes([value[0], value[1]]))
+                        .collect();
+                    // Only the SP can be refused, so it goes first and nothing is half written
+                    match self.write_register(SP_REGISTER, values[SP_REGISTER]) {
+                        Ok(()) => {
+                            for (register, value) in values.into_iter().enumerate() {
+                                let _ = self.write_register(register, value);
+                            }
+                            "OK".to_string()
+                        }
+                        Err(_) => "E01".to_string(),
+                    }
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(register) if register < REGISTER_COUNT => {
+                    to_hex(&self.read_register(register).to_le_bytes())
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let write = args.split_once('=').and_then(|(register, value)| {
+                    let register = usize::from_str_radix(register, 16).ok()?;
+                    let value = from_hex(value)?;
+                    (register < REGISTER_COUNT && value.len() == 2).then_some((register, value))
+                });
+                let written = write.map(|(register, value)| {
+                    self.write_register(register, u16::from_le_bytes([value[0], value[1]]))
+                });
+                match written {
+                    Some(Ok(())) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_address_length(args) {
+                Some((address, length)) => {
+                    let bytes: Vec<u8> = (address..address.saturating_add(length))
+                        .map_while(|byte| self.read_byte_at(byte))
+                        .collect();
+                    if bytes.is_empty() && length > 0 {
+                        "E14".to_string()
+                    } else {
+                        to_hex(&bytes)
+                    }
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_address_length(range)?;
+                    let data = from_hex(data)?;
+                    (data.len() == length).then_some((address, data))
+                });
+                match write {
+                    Some((address, data))
+                        if address.saturating_add(data.len()) <= MEMORY_SIZE * 2 =>
+                    {
+                        for (i, byte) in data.into_iter().enumerate() {
+                            self.write_byte_at(address + i, byte);
+                        }
+                        "OK".to_string()
+                    }
+                    Some(_) => "E14".to_string(),
+                    None => "E01".to_string(),
+                }
+            }
+            "s" | "c" => {
+                if let Ok(address) = u16::from_str_radix(args, 16) {
+                    self.cpu.set_pc(address / 2);
+                }
+                let result = if command == "s" {
+                    Some(self.cpu.step())
+                } else {
+                    self.resume()?
+                };
+                self.last_stop = self.stop_reply(result)?;
+                self.last_stop.clone()
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "H" | "T" => "OK".to_string(), // There is only one thread
+            "q" => self.query(args)?,
+            "Q" if args == "StartNoAckMode" => "OK".to_string(),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&mut self, args: &str) -> io::Result<String> {
+        let reply = if args.starts_with("Supported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let xml = target_xml();
+                    let end = offset.saturating_add(length);
+                    let chunk = xml.get(offset.min(xml.len())..end.min(xml.len()));
+                    match chunk {
+                        Some(chunk) if end < xml.len() => format!("m{}", chunk),
+                        Some(chunk) => format!("l{}", chunk),
+                        None => "E01".to_string(),
+                    }
+                }
+                None => "E01".to_string(),
+            }
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if args == "C" {
+            "QC1".to_string()
+        } else if args == "fThreadInfo" {
+            "m1".to_string()
+        } else if args == "sThreadInfo" {
+            "l".to_string()
+        } else if let Some(command) = args.strip_prefix("Rcmd,") {
+            let command =
+                from_hex(command).map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+            match command {
+                Some(command) => self.monitor(&command)?,
+                None => "E01".to_string(),
+            }
+        } else {
+            String::new()
+        };
+        Ok(reply)
+    }
+
+    /// Runs a `monitor` command from the client.
+    fn monitor(&mut self, command: &str) -> io::Result<String> {
+        let mut tokens = command.split_whitespace();
+        match tokens.next() {
+            Some("input") => {
+                for token in tokens {
+                    let value = token
+                        .parse::<u16>()
+                        .ok()
+                        .or_else(|| token.parse::<i16>().ok().map(|value| value as u16));
+                    match value {
+                        Some(value) => self.cpu.input.push_back(value),
+                        None => self.send_output(&format!("Not a number: \"{}\"\n", token))?,
+                    }
+                }
+            }
+            Some("reset") => self.reset(),
+            _ => self.send_output("Monitor commands: input <numbers...>, reset\n")?,
+        }
+        Ok("OK".to_string())
+    }
+
+    /// Runs until something stops the CPU or the client interrupts it.
+    fn resume(&mut self) -> io::Result<Option<StepResult>> {
+        loop {
+            let result = self.cpu.run_for(INTERRUPT_INTERVAL);
+            if result != StepResult::Executed {
+                return Ok(Some(result));
+            }
+            if self.interrupted()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Checks for an interrupt from the client without waiting for one.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.connection.set_nonblocking(true)?;
+        let mut buffer = [0; 1024];
+        let read = self.connection.read(&mut buffer);
+        self.connection.set_nonblocking(false)?;
+        match read {
+            Ok(read) => self.pending.extend(&buffer[..read]),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error),
+        }
+        match self.pending.iter().position(|&byte| byte == 0x03) {
+            Some(position) => {
+                self.pending.remove(position);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Builds the stop reply for a step, or None if the client interrupted the program.
+    fn stop_reply(&mut self, result: Option<StepResult>) -> io::Result<String> {
+        let signal = match result {
+            None => SIGINT,
+            Some(StepResult::Executed) | Some(StepResult::Breakpoint(_)) => SIGTRAP,
+            Some(StepResult::Halted) => return Ok("W00".to_string()),
+            Some(StepResult::WaitingForInput) => {
+                self.send_output(
+                    "The program is waiting for input, use `monitor input <number>`.\n",
+                )?;
+                SIGTRAP
+            }
+            Some(StepResult::Fault(error)) => {
+                self.send_output(&format!("{}\n", error))?;
+                match error.fault {
+                    Fault::UnknownInstruction => SIGILL,
+                    Fault::DivideByZero | Fault::Overflow(_) | Fault::NegativeResult(_) => SIGFPE,
+                    Fault::MemoryOutOfBounds(_)
+                    | Fault::StackOverflow
+                    | Fault::StackUnderflow
+                    | Fault::StackPointerOutOfBounds(_) => SIGSEGV,
+                    Fault::NoInput => SIGTRAP,
+                }
+            }
+        };
+        Ok(format!("S{:02x}", signal))
+    }
+
+    /// Sets or clears a breakpoint from a Z or z packet.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        // Software and hardware breakpoints are the same thing here, watchpoints aren't supported
+        let Some(args) = args.strip_prefix("0,").or_else(|| args.strip_prefix("1,")) else {
+            return String::new();
+        };
+        match parse_address_length(args) {
+            Some((address, _)) if address.is_multiple_of(2) && address < MEMORY_SIZE * 2 => {
+                let address = (address / 2) as u16;
+                if insert {
+                    self.cpu.breakpoints.insert(address);
+                } else {
+                    self.cpu.breakpoints.remove(&address);
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_register(&self, register: usize) -> u16 {
+        match register {
+            PC_REGISTER => self.cpu.pc.wrapping_mul(2),
+            SP_REGISTER => self.cpu.sp.wrapping_mul(2),
+            FLAGS_REGISTER => self.cpu.flags,
+            _ => self.cpu.registers[register],
+        }
+    }
+
+    /// Writes a register, unless it's an SP outside of memory.
+    fn write_register(&mut self, register: usize, value: u16) -> Result<(), Fault> {
+        match register {
+            // G writes every register, so writing the PC it already has isn't a jump
+            PC_REGISTER if value / 2 == self.cpu.pc => {}
+            PC_REGISTER => self.cpu.set_pc(value / 2),
+            SP_REGISTER => self.cpu.set_sp(value / 2)?,
+            FLAGS_REGISTER => self.cpu.flags = value,
+            _ => self.cpu.registers[register] = value,
+        }
+        Ok(())
+    }
+
+    fn read_byte_at(&self, address: usize) -> Option<u8> {
+        let word = *self.cpu.memory.get(address / 2)?;
+        Some(word.to_le_bytes()[address % 2])
+    }
+
+    fn write_byte_at(&mut self, address: usize, byte: u8) {
+        let mut bytes = self.cpu.memory[address / 2].to_le_bytes();
+        bytes[address % 2] = byte;
+        self.cpu.memory[address / 2] = u16::from_le_bytes(bytes);
+    }
+}
+
+/// Waits for one client on a local TCP port, then serves it until it disconnects.
+pub fn serve_tcp(
+    program: Vec<Instruction>,
+    arithmetic: ArithmeticMode,
+    port: u16,
+) -> Result<(), Error> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| Error::Io(format!("Error listening on port {}", port), e))?;
+    println!("Waiting for a GDB client on 127.0.0.1:{}", port);
+    let (stream, address) = listener
+        .accept()
+        .map_err(|e| Error::Io("Error accepting a GDB client".to_string(), e))?;
+    println!("GDB client connected from {}", address);
+    stream.set_nodelay(true).ok();
+    GdbStub::new(program, arithmetic, stream)
+        .serve()
+        .map_err(|e| Error::Io("GDB connection error".to_string(), e))
+}
+
+/// Waits for one client on a Unix socket, then serves it until it disconnects.
+#[cfg(unix)]
+pub fn serve_unix(
+    program: Vec<Instruction>,
+    arithmetic: ArithmeticMode,
+    path: &str,
+) -> Result<(), Error> {
+    let listener = UnixListener::bind(path)
+        .map_err(|e| Error::Io(format!("Error listening on {}", path), e))?;
+    println!("Waiting for a GDB client on {}", path);
+    let result = listener
+        .accept()
+        .map_err(|e| Error::Io("Error accepting a GDB client".to_string(), e))
+        .and_then(|(stream, _)| {
+            println!("GDB client connected");
+            GdbStub::new(program, arithmetic, stream)
+                .serve()
+                .map_err(|e| Error::Io("GDB connection error".to_string(), e))
+        });
+    std::fs::remove_file(path).ok();
+    result
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// A client on the other end of an in-memory connection to a stub.
+    struct Client {
+        stream: UnixStream,
+        stub: Option<thread::JoinHandle<io::Result<()>>>,
+    }
+
+    impl Client {
+        fn new(program: Vec<Instruction>) -> Client {
+            let (stream, server) = UnixStream::pair().unwrap();
+            let stub = thread::spawn(move || {
+                GdbStub::new(program, ArithmeticMode::default(), server).serve()
+            });
+            Client {
+                stream,
+                stub: Some(stub),
+            }
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        /// Sends a packet and returns the reply, skipping acknowledgements and console output.
+        fn request(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+            loop {
+                while self.read_byte() != b'$' {}
+                let mut reply = Vec::new();
+                loop {
+                    match self.read_byte() {
+                        b'#' => break,
+                        byte => reply.push(byte),
+                    }
+                }
+                let checksum = [self.read_byte(), self.read_byte()];
+                let expected = reply.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+                assert_eq!(checksum, format!("{:02x}", expected).as_bytes());
+                let reply = String::from_utf8(reply).unwrap();
+                if !reply.starts_with('O') || reply == "OK" {
+                    return reply;
+                }
+            }
+        }
+
+        /// Detaches and waits for the stub to finish.
+        fn detach(mut self) {
+            assert_eq!(self.request("D"), "OK");
+            self.stub.take().unwrap().join().unwrap().unwrap();
+        }
+    }
+
+    fn program() -> Vec<Instruction> {
+        vec![
+            Instruction::MOV(0, 5),
+            Instruction::INC(0),
+            Instruction::INC(0),
+            Instruction::HALT,
+        ]
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut client = Client::new(program());
+        let registers = client.request("g");
+        assert_eq!(registers.len(), REGISTER_COUNT * 4);
+        assert!(registers.starts_with("0000"));
+
+        let mut written = String::from("3412");
+        written += &"0000".repeat(REGISTER_COUNT - 1);
+        assert_eq!(client.request(&format!("G{}", written)), "OK");
+        assert_eq!(client.request("g"), written);
+        assert_eq!(client.request("Gzz"), "E01");
+
+        // The SP, register 0x11, can't be moved past the end of memory
+        let sp = to_hex(&((MEMORY_SIZE as u16 + 1) * 2).to_le_bytes());
+        assert_eq!(client.request(&format!("P11={}", sp)), "E01");
+        let mut refused = String::from("7856");
+        refused += &"0000".repeat(SP_REGISTER - 1);
+        refused += &sp;
+        refused += "0000";
+        assert_eq!(client.request(&format!("G{}", refused)), "E01");
+        assert_eq!(client.request("g"), written);
+        client.detach();
+    }
+
+    #[test]
+    fn reads_and_writes_memory_as_bytes() {
+        let mut client = Client::new(program());
+        let first = CPU::encode_instruction(&Instruction::MOV(0, 5))[0];
+        assert_eq!(client.request("m0,2"), to_hex(&first.to_le_bytes()));
+
+        assert_eq!(client.request("M20,3:abcdef"), "OK");
+        assert_eq!(client.request("m20,4"), "abcdef00");
+        assert_eq!(
+            client.request(&format!("M{:x},2:0000", MEMORY_SIZE * 2)),
+            "E14"
+        );
+        client.detach();
+    }
+
+    #[test]
+    fn continues_to_a_breakpoint_and_then_exits() {
+        let mut client = Client::new(program());
+        assert_eq!(client.request("Z0,4,2"), "OK"); // The second INC, at word 2
+        assert_eq!(client.request("c"), format!("S{:02x}", SIGTRAP));
+        assert_eq!(client.request("p10"), "0400"); // The PC, as a byte address
+        assert_eq!(client.request("p0"), "0600");
+
+        assert_eq!(client.request("z0,4,2"), "OK");
+        assert_eq!(client.request("c"), "W00");
+        assert_eq!(client.request("p0"), "0700");
+        client.detach();
+    }
+
+    #[test]
+    fn steps_one_instruction() {
+        let mut client = Client::new(program());
+        assert_eq!(client.request("s"), format!("S{:02x}", SIGTRAP));
+        assert_eq!(client.request("p10"), "0200");
+        assert_eq!(client.request("p0"), "0500");
+        assert_eq!(client.request("?"), format!("S{:02x}", SIGTRAP));
+        client.detach();
+    }
+}
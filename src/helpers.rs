@@ -1,25 +1,13 @@
 use crate::*;
-use std::process;
 
 // these are helper functions, not missiong critical, they help.
 
-pub fn neg_num_err(instruction: &str) {
-    eprintln!(
-        "{}{}{}",
-        "ERROR, ".color(Colors::RedFg),
-        instruction.color(Colors::YellowFg),
-        " WILL RESULT IN NEGATIVE NUMBER.\nTERMINATING.".color(Colors::RedFg)
-    );
-    process::exit(0);
-}
-
 pub fn err_print(error: String) {
     eprintln!(
         "{}{}",
         "ERROR, ".color(Colors::RedFg),
         error.color(Colors::RedFg)
     );
-    process::exit(0);
 }
 
 pub fn letter_to_integer(letter: char) -> Option<u8> {
@@ -32,31 +20,12 @@ pub fn letter_to_integer(letter: char) -> Option<u8> {
     }
 }
 
-pub fn integer_to_letter(n: usize) -> char {
+pub fn integer_to_letter(n: usize) -> Option<char> {
     if n < 26 {
-        (n as u8 + b'a') as char
+        Some((n as u8 + b'a') as char)
     } else {
-        err_print("value passed to integer_to_letter was too large.".to_string());
-        process::exit(0);
-    }
-}
-
-pub fn has_b_with_num(s: &str) -> bool {
-    let bytes = s.as_bytes();
-    let mut found_b = false;
-    for &byte in bytes {
-        if found_b {
-            if byte.is_ascii_digit() {
-                return true;
-            } else {
-                found_b = false; // reset if not a digit
-            }
-        }
-        if byte == b'b' || byte == b'B' {
-            found_b = true;
-        }
+        None
     }
-    false
 }
 
 #[allow(dead_code)]
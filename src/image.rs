@@ -0,0 +1,376 @@
+use crate::*;
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const RECORD_SIZE: usize = 16; // Data bytes per Intel HEX or S-record line
+
+/// The file formats a binary can be written in. HEX and S-record images address memory in
+/// bytes, with each word stored little-endian, so a word at address N is at bytes 2N and 2N+1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    Binary, // The alice binary format
+    Ihex,   // Intel HEX
+    Srec,   // Motorola S-record
+}
+
+impl ImageFormat {
+    /// Picks a format from a file extension, defaulting to the alice binary format.
+    pub fn from_extension(filename: &str) -> ImageFormat {
+        let extension = Path::new(filename)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("hex" | "ihex" | "ihx") => ImageFormat::Ihex,
+            Some("srec" | "s19" | "mot") => ImageFormat::Srec,
+            _ => ImageFormat::Binary,
+        }
+    }
+
+    /// Works out the format of a file from its first bytes.
+    pub fn sniff(bytes: &[u8]) -> Option<ImageFormat> {
+        if bytes.starts_with(&MAGIC) {
+            return Some(ImageFormat::Binary);
+        }
+        let start = bytes.iter().position(|byte| !byte.is_ascii_whitespace())?;
+        match &bytes[start..] {
+            [b':', ..] => Some(ImageFormat::Ihex),
+            [b'S', digit, ..] if digit.is_ascii_digit() => Some(ImageFormat::Srec),
+            _ => None,
+        }
+    }
+}
+
+/// Splits every section into runs of at most RECORD_SIZE bytes, along with their byte address.
+fn data_records(binary: &Binary) -> Vec<(usize, Vec<u8>)> {
+    let mut records = Vec::new();
+    for section in &binary.sections {
+        let bytes: Vec<u8> = section
+            .words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        for (i, chunk) in bytes.chunks(RECORD_SIZE).enumerate() {
+            records.push((
+                section.address as usize * 2 + i * RECORD_SIZE,
+                chunk.to_vec(),
+            ));
+        }
+    }
+    records
+}
+
+/// Formats a record, appending the checksum computed from all of its bytes.
+fn record(prefix: &str, bytes: &[u8], checksum: impl Fn(u8) -> u8) -> String {
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    let mut line = prefix.to_string();
+    for byte in bytes.iter().chain([checksum(sum)].iter()) {
+        line += &format!("{:02X}", byte);
+    }
+    line + "\n"
+}
+
+fn ihex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    record(":", &bytes, |sum| sum.wrapping_neg())
+}
+
+fn srec_record(kind: char, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8 + 3]; // Address, data and checksum
+    bytes.extend(address.to_be_bytes());
+    bytes.extend(data);
+    record(&format!("S{}", kind), &bytes, |sum| !sum)
+}
+
+/// Writes a binary as Intel HEX, with the entry point as a start linear address record.
+pub fn to_ihex(binary: &Binary) -> String {
+    let mut text = String::new();
+    for (address, data) in data_records(binary) {
+        text += &ihex_record(0x00, address as u16, &data);
+    }
+    let entry = (binary.entry as u32 * 2).to_be_bytes();
+    text += &ihex_record(0x05, 0, &entry);
+    text += &ihex_record(0x01, 0, &[]);
+    text
+}
+
+/// Writes a binary as S-records, with the entry point in the S9 termination record.
+pub fn to_srec(binary: &Binary) -> String {
+    let mut text = srec_record('0', 0, b"alice");
+    let records = data_records(binary);
+    for (address, data) in &records {
+        text += &srec_record('1', *address as u16, data);
+    }
+    text += &srec_record('5', records.len() as u16, &[]);
+    text += &srec_record('9', binary.entry * 2, &[]);
+    text
+}
+
+/// Parses the hex digits of a record into bytes.
+fn record_bytes(digits: &str, line: usize) -> Result<Vec<u8>, BinaryError> {
+    let digits = digits.trim_end();
+    if !digits.len().is_multiple_of(2) || !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return Err(BinaryError::InvalidRecord(line));
+    }
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or(0))
+        .collect())
+}
+
+/// Reads a big-endian address of any length.
+fn be_address(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0, |address, byte| address << 8 | *byte as u32)
+}
+
+/// Turns loaded bytes and a start address into a binary, with each run of words as a code
+/// section, since these formats don't say what is code and what is data.
+fn to_binary(
+    bytes: BTreeMap<u32, u8>,
+    start: Option<u32>,
+    line: usize,
+) -> Result<Binary, BinaryError> {
+    let mut sections: Vec<Section> = Vec::new();
+    for (&byte_address, &low) in bytes.iter().filter(|(address, _)| *address % 2 == 0) {
+        let address = byte_address / 2;
+        let high = *bytes
+            .get(&(byte_address + 1))
+            .ok_or(BinaryError::IncompleteWord(
+                address.min(u16::MAX as u32) as u16
+            ))?;
+        if address as usize >= MEMORY_SIZE {
+            return Err(BinaryError::SectionOutOfMemory(
+                address.min(u16::MAX as u32) as u16,
+                1,
+            ));
+        }
+        let word = u16::from_le_bytes([low, high]);
+        match sections.last_mut() {
+            Some(section) if (section.address as usize + section.words.len()) as u32 == address => {
+                section.words.push(word)
+            }
+            _ => sections.push(Section {
+                kind: SectionKind::Code,
+                address: address as u16,
+                words: vec![word],
+            }),
+        }
+    }
+    // Odd bytes without the even byte before them
+    if let Some((&byte_address, _)) = bytes
+        .iter()
+        .find(|(address, _)| *address % 2 == 1 && !bytes.contains_key(&(*address - 1)))
+    {
+        return Err(BinaryError::IncompleteWord(
+            (byte_address / 2).min(u16::MAX as u32) as u16,
+        ));
+    }
+
+    let entry = match start {
+        Some(start) if start % 2 == 0 && start / 2 <= u16::MAX as u32 => (start / 2) as u16,
+        Some(_) => return Err(BinaryError::InvalidRecord(line)),
+        None => 0,
+    };
+    let binary = Binary { entry, sections };
+    binary.validate()?;
+    Ok(binary)
+}
+
+/// Reads an Intel HEX image, checking the checksum of every record.
+pub fn from_ihex(text: &str) -> Result<Binary, BinaryError> {
+    let mut bytes = BTreeMap::new();
+    let mut start = None;
+    let mut base = 0u32; // From extended segment and linear address records
+    let mut end_line = None;
+
+    for (index, text_line) in text.lines().enumerate() {
+        let line = index + 1;
+        let text_line = text_line.trim();
+        if text_line.is_empty() {
+            continue;
+        }
+        if end_line.is_some() {
+            return Err(BinaryError::InvalidRecord(line)); // Records after the end of file record
+        }
+        let digits = text_line
+            .strip_prefix(':')
+            .ok_or(BinaryError::InvalidRecord(line))?;
+        let record = record_bytes(digits, line)?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(BinaryError::InvalidRecord(line));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(BinaryError::BadChecksum(line));
+        }
+
+        let address = be_address(&record[1..3]);
+        let data = &record[4..record.len() - 1];
+        match (record[3], data.len()) {
+            (0x00, _) => {
+                for (i, byte) in data.iter().enumerate() {
+                    // A record can't run past the end of the 32 bit address space
+                    let address = (base + address)
+                        .checked_add(i as u32)
+                        .ok_or(BinaryError::InvalidRecord(line))?;
+                    bytes.insert(address, *byte);
+                }
+            }
+            (0x01, 0) => end_line = Some(line),
+            (0x02, 2) => base = be_address(data) << 4,
+            (0x03, 4) => start = Some((be_address(&data[..2]) << 4) + be_address(&data[2..])),
+            (0x04, 2) => base = be_address(data) << 16,
+            (0x05, 4) => start = Some(be_address(data)),
+            _ => return Err(BinaryError::InvalidRecord(line)),
+        }
+    }
+
+    let line = end_line.ok_or(BinaryError::MissingEndRecord)?;
+    to_binary(bytes, start, line)
+}
+
+/// Reads an S-record image, checking the checksum of every record.
+pub fn from_srec(text: &str) -> Result<Binary, BinaryError> {
+    let mut bytes = BTreeMap::new();
+    let mut start = None;
+    let mut data_records = 0;
+    let mut end_line = None;
+
+    for (index, text_line) in text.lines().enumerate() {
+        let line = index + 1;
+        let text_line = text_line.trim();
+        if text_line.is_empty() {
+            continue;
+        }
+        if end_line.is_some() {
+            return Err(BinaryError::InvalidRecord(line)); // Records after the termination record
+        }
+        let mut chars = text_line.chars();
+        let (Some('S'), Some(kind)) = (chars.next(), chars.next()) else {
+            return Err(BinaryError::InvalidRecord(line));
+        };
+        let record = record_bytes(chars.as_str(), line)?;
+        if record.is_empty() || record.len() != record[0] as usize + 1 {
+            return Err(BinaryError::InvalidRecord(line));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+            return Err(BinaryError::BadChecksum(line));
+        }
+
+        let address_size = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(BinaryError::InvalidRecord(line)),
+        };
+        if record.len() < address_size + 2 {
+            return Err(BinaryError::InvalidRecord(line));
+        }
+        let address = be_address(&record[1..1 + address_size]);
+        let data = &record[1 + address_size..record.len() - 1];
+        match kind {
+            '0' => {} // Header
+            '1' | '2' | '3' => {
+                for (i, byte) in data.iter().enumerate() {
+                    let address = address
+                        .checked_add(i as u32)
+                        .ok_or(BinaryError::InvalidRecord(line))?;
+                    bytes.insert(address, *byte);
+                }
+                data_records += 1;
+            }
+            '5' | '6' => {
+                if address != data_records {
+                    return Err(BinaryError::InvalidRecord(line)); // Records went missing
+                }
+            }
+            _ => {
+                start = Some(address);
+                end_line = Some(line);
+            }
+        }
+    }
+
+    let line = end_line.ok_or(BinaryError::MissingEndRecord)?;
+    to_binary(bytes, start, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Binary {
+        Binary {
+            entry: 1,
+            sections: vec![
+                Section {
+                    kind: SectionKind::Code,
+                    address: 0,
+                    words: (0..20).map(|word| word * 0x0101).collect(),
+                },
+                Section {
+                    kind: SectionKind::Code,
+                    address: 100,
+                    words: vec![0xBEEF, 0],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn round_trips_through_ihex() {
+        let text = to_ihex(&sample());
+        assert!(text.ends_with(":00000001FF\n"));
+        assert_eq!(from_ihex(&text), Ok(sample()));
+    }
+
+    #[test]
+    fn round_trips_through_srec() {
+        let text = to_srec(&sample());
+        assert!(text.starts_with("S0"));
+        assert_eq!(from_srec(&text), Ok(sample()));
+    }
+
+    #[test]
+    fn rejects_bad_checksums_and_missing_ends() {
+        let text = to_ihex(&sample()).replacen(":10", ":11", 1);
+        assert_eq!(from_ihex(&text), Err(BinaryError::InvalidRecord(1)));
+        let text = to_ihex(&sample()).replacen("0101", "0102", 1);
+        assert_eq!(from_ihex(&text), Err(BinaryError::BadChecksum(1)));
+        let text = ihex_record(0x00, 0, &[1, 0]);
+        assert_eq!(from_ihex(&text), Err(BinaryError::MissingEndRecord));
+
+        let text = to_srec(&sample()).replacen("0101", "0102", 1);
+        assert_eq!(from_srec(&text), Err(BinaryError::BadChecksum(2)));
+        let text = srec_record('1', 0, &[1, 0]);
+        assert_eq!(from_srec(&text), Err(BinaryError::MissingEndRecord));
+    }
+
+    #[test]
+    fn rejects_half_words_and_addresses_outside_memory() {
+        let text = ihex_record(0x00, 0, &[1]) + &ihex_record(0x01, 0, &[]);
+        assert_eq!(from_ihex(&text), Err(BinaryError::IncompleteWord(0)));
+        let address = MEMORY_SIZE as u16 * 2;
+        let text = ihex_record(0x00, address, &[1, 0]) + &ihex_record(0x01, 0, &[]);
+        assert_eq!(
+            from_ihex(&text),
+            Err(BinaryError::SectionOutOfMemory(MEMORY_SIZE as u16, 1))
+        );
+    }
+
+    #[test]
+    fn rejects_records_past_the_end_of_the_address_space() {
+        let text = ihex_record(0x04, 0, &[0xFF, 0xFF])
+            + &ihex_record(0x00, 0xFFF8, &[0; 16])
+            + &ihex_record(0x01, 0, &[]);
+        assert_eq!(from_ihex(&text), Err(BinaryError::InvalidRecord(2)));
+
+        let s3 = record("S3", &[7, 0xFF, 0xFF, 0xFF, 0xFF, 1, 2], |sum| !sum);
+        let text = s3 + &srec_record('9', 0, &[]);
+        assert_eq!(from_srec(&text), Err(BinaryError::InvalidRecord(1)));
+    }
+}
@@ -1,4 +1,8 @@
+use crate::*;
+use std::fmt;
+
 pub const MEMORY_SIZE: usize = 255;
+pub const STACK_SIZE: usize = 32; // The stack takes up the top of memory and grows down
 
 // Opcode constants
 pub const ADD_OPCODE: u16 = 0x1;
@@ -15,11 +19,63 @@ pub const POW_OPCODE: u16 = 0xb;
 pub const MOVR_OPCODE: u16 = 0xc;
 pub const CMP_OPCODE: u16 = 0xd;
 pub const JMP_OPCODE: u16 = 0xe;
-pub const NOP_OPCODE: u16 = 0xf;
+pub const EXT_OPCODE: u16 = 0xf; // Escape into the extended opcodes below
 pub const HALT_OPCODE: u16 = 0x0;
 
+// Extended opcodes, stored in the low 8 bits of an EXT instruction.
+// Opcodes from EXT_TWO_WORD up are followed by a second operand word.
+pub const EXT_TWO_WORD: u16 = 0x80;
+pub const EXT_NOP: u16 = 0x00; // 0xf000, the same word NOP had as a primary opcode
+pub const EXT_PUSH: u16 = 0x01;
+pub const EXT_POP: u16 = 0x02;
+pub const EXT_RET: u16 = 0x03;
+pub const EXT_NOT: u16 = 0x04;
+pub const EXT_IN: u16 = 0x05; // Reads a number from the input
+pub const EXT_LOAD: u16 = 0x80; // Operand is the address
+pub const EXT_LOAD_INDEXED: u16 = 0x81; // Operand is the base register << 12 | offset
+pub const EXT_STORE: u16 = 0x82;
+pub const EXT_STORE_INDEXED: u16 = 0x83;
+pub const EXT_CALL: u16 = 0x84; // Operand is the address
+pub const EXT_IMUL: u16 = 0x85; // Operand is the source register << 12
+pub const EXT_IDIV: u16 = 0x86;
+pub const EXT_ICMP: u16 = 0x87;
+pub const EXT_AND: u16 = 0x88; // Operand is the source register << 12
+pub const EXT_OR: u16 = 0x89;
+pub const EXT_XOR: u16 = 0x8a;
+pub const EXT_SHL: u16 = 0x8b; // Operand is the shift count
+pub const EXT_SHR: u16 = 0x8c;
+pub const EXT_ROL: u16 = 0x8d;
+pub const EXT_ROR: u16 = 0x8e;
+
+// Jump conditions, stored in the DEST field of JMP
+pub const COND_ALWAYS: u16 = 0x0;
+pub const COND_ZERO: u16 = 0x1;
+pub const COND_NOT_ZERO: u16 = 0x2;
+pub const COND_CARRY: u16 = 0x3;
+pub const COND_NOT_CARRY: u16 = 0x4;
+pub const COND_NEGATIVE: u16 = 0x5;
+pub const COND_NOT_NEGATIVE: u16 = 0x6;
+pub const COND_OVERFLOW: u16 = 0x7;
+pub const COND_NOT_OVERFLOW: u16 = 0x8;
+pub const COND_ABOVE: u16 = 0x9; // Unsigned >
+pub const COND_BELOW_EQUAL: u16 = 0xa; // Unsigned <=
+pub const COND_LESS: u16 = 0xb; // Signed <
+pub const COND_GREATER_EQUAL: u16 = 0xc; // Signed >=
+pub const COND_GREATER: u16 = 0xd; // Signed >
+pub const COND_LESS_EQUAL: u16 = 0xe; // Signed <=
+
+/// Number of words an encoded instruction takes up, based on its first word.
+pub fn instruction_length(word: u16) -> u16 {
+    if word >> 12 == EXT_OPCODE && word & 0xFF >= EXT_TWO_WORD {
+        2
+    } else {
+        1
+    }
+}
+
 // Add instructions here
-#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Instruction {
     ADD(u16, u16),
     MOV(u16, u16),
@@ -35,6 +91,306 @@ pub enum Instruction {
     MOVR(u16, u16),
     CMP(u16, u16),
     JMP(u16),
+    JZ(u16),
+    JNZ(u16),
+    JC(u16),
+    JNC(u16),
+    JN(u16),
+    JNN(u16),
+    JO(u16),
+    JNO(u16),
+    JA(u16),
+    JBE(u16),
+    JL(u16),
+    JGE(u16),
+    JG(u16),
+    JLE(u16),
+    LOAD(u16, u16),        // Register, address
+    LOADX(u16, u16, u16),  // Register, base register, offset
+    STORE(u16, u16),       // Address, register
+    STOREX(u16, u16, u16), // Base register, offset, register
+    IMUL(u16, u16),
+    IDIV(u16, u16),
+    ICMP(u16, u16),
+    AND(u16, u16),
+    OR(u16, u16),
+    XOR(u16, u16),
+    NOT(u16),
+    SHL(u16, u16), // Register, shift count
+    SHR(u16, u16),
+    ROL(u16, u16),
+    ROR(u16, u16),
+    IN(u16),
+    PUSH(u16),
+    POP(u16),
+    CALL(u16),
+    RET,
     NOP,
     HALT,
+    WORD(u16), // A raw word that isn't an instruction, like data
+}
+
+/// Decodes an instruction from its first word and its operand word, which is ignored for
+/// one-word instructions. Words that don't decode to exactly what the assembler would emit,
+/// like unknown opcodes or stray bits in unused fields, come back as WORD.
+pub fn decode_instruction(word: u16, operand: u16) -> Instruction {
+    let opcode = word >> 12;
+    let reg1 = (word >> 8) & 0xF;
+    let reg2 = (word >> 4) & 0xF;
+    let value = word & 0xFF;
+    let two_registers = word & 0xF == 0; // Low nibble is unused
+    let one_register = word & 0xF0F == 0; // Only the SRC field is used
+    let in_memory = (value as usize) < MEMORY_SIZE; // A jump target
+
+    match opcode {
+        ADD_OPCODE if two_registers => Instruction::ADD(reg1, reg2),
+        MOV_OPCODE => Instruction::MOV(reg1, value),
+        MUL_OPCODE if two_registers => Instruction::MUL(reg1, reg2),
+        SUB_OPCODE if two_registers => Instruction::SUB(reg1, reg2),
+        SWAP_OPCODE if two_registers => Instruction::SWAP(reg1, reg2),
+        DIV_OPCODE if two_registers => Instruction::DIV(reg1, reg2),
+        CLR_OPCODE if one_register => Instruction::CLR(reg2),
+        INC_OPCODE if one_register => Instruction::INC(reg2),
+        DEC_OPCODE if one_register => Instruction::DEC(reg2),
+        PRINT_OPCODE if one_register => Instruction::PRINT(reg2),
+        POW_OPCODE => Instruction::POW(reg1, value),
+        MOVR_OPCODE if two_registers => Instruction::MOVR(reg1, reg2),
+        CMP_OPCODE if two_registers => Instruction::CMP(reg1, reg2),
+        JMP_OPCODE if in_memory => match reg1 {
+            COND_ALWAYS => Instruction::JMP(value),
+            COND_ZERO => Instruction::JZ(value),
+            COND_NOT_ZERO => Instruction::JNZ(value),
+            COND_CARRY => Instruction::JC(value),
+            COND_NOT_CARRY => Instruction::JNC(value),
+            COND_NEGATIVE => Instruction::JN(value),
+            COND_NOT_NEGATIVE => Instruction::JNN(value),
+            COND_OVERFLOW => Instruction::JO(value),
+            COND_NOT_OVERFLOW => Instruction::JNO(value),
+            COND_ABOVE => Instruction::JA(value),
+            COND_BELOW_EQUAL => Instruction::JBE(value),
+            COND_LESS => Instruction::JL(value),
+            COND_GREATER_EQUAL => Instruction::JGE(value),
+            COND_GREATER => Instruction::JG(value),
+            COND_LESS_EQUAL => Instruction::JLE(value),
+            _ => Instruction::WORD(word),
+        },
+        EXT_OPCODE => decode_extended(word, operand),
+        HALT_OPCODE if word == 0 => Instruction::HALT,
+        _ => Instruction::WORD(word),
+    }
+}
+
+/// Decodes an instruction from the extended opcode space.
+fn decode_extended(word: u16, operand: u16) -> Instruction {
+    let reg = (word >> 8) & 0xF;
+    let src = operand >> 12;
+    let no_register = reg == 0;
+    let register_operand = operand & 0xFFF == 0; // Only the top nibble is used
+    let indexed_operand = operand & 0xF00 == 0; // Base register and an 8 bit offset
+    let address = (operand as usize) < MEMORY_SIZE;
+    let count = operand <= 15; // The CPU only uses the low 4 bits of a shift count
+
+    match word & 0xFF {
+        EXT_NOP if no_register => Instruction::NOP,
+        EXT_PUSH => Instruction::PUSH(reg),
+        EXT_POP => Instruction::POP(reg),
+        EXT_RET if no_register => Instruction::RET,
+        EXT_NOT => Instruction::NOT(reg),
+        EXT_IN => Instruction::IN(reg),
+        EXT_LOAD if address => Instruction::LOAD(reg, operand),
+        EXT_LOAD_INDEXED if indexed_operand => Instruction::LOADX(reg, src, operand & 0xFF),
+        EXT_STORE if address => Instruction::STORE(operand, reg),
+        EXT_STORE_INDEXED if indexed_operand => Instruction::STOREX(src, operand & 0xFF, reg),
+        EXT_CALL if no_register && address => Instruction::CALL(operand),
+        EXT_IMUL if register_operand => Instruction::IMUL(reg, src),
+        EXT_IDIV if register_operand => Instruction::IDIV(reg, src),
+        EXT_ICMP if register_operand => Instruction::ICMP(reg, src),
+        EXT_AND if register_operand => Instruction::AND(reg, src),
+        EXT_OR if register_operand => Instruction::OR(reg, src),
+        EXT_XOR if register_operand => Instruction::XOR(reg, src),
+        EXT_SHL if count => Instruction::SHL(reg, operand),
+        EXT_SHR if count => Instruction::SHR(reg, operand),
+        EXT_ROL if count => Instruction::ROL(reg, operand),
+        EXT_ROR if count => Instruction::ROR(reg, operand),
+        _ => Instruction::WORD(word),
+    }
+}
+
+/// Formats a register number as its name, like `ax`.
+fn register_name(reg: u16) -> String {
+    format!("{}x", integer_to_letter(reg as usize).unwrap_or('?'))
+}
+
+/// Formats a base register and offset as an indexed memory operand, like `[bx+3]`.
+fn indexed_operand(base: u16, offset: u16) -> String {
+    if offset == 0 {
+        format!("[{}]", register_name(base))
+    } else {
+        format!("[{}+{}]", register_name(base), offset)
+    }
+}
+
+// Formats an instruction as the assembly that produces it
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = |reg: &u16| register_name(*reg);
+        match self {
+            Instruction::ADD(dst, src) => write!(f, "add {}, {}", r(dst), r(src)),
+            Instruction::MOV(dst, value) => write!(f, "mov {}, {}", r(dst), value),
+            Instruction::MUL(dst, src) => write!(f, "mul {}, {}", r(dst), r(src)),
+            Instruction::SUB(dst, src) => write!(f, "sub {}, {}", r(dst), r(src)),
+            Instruction::SWAP(dst, src) => write!(f, "swap {}, {}", r(dst), r(src)),
+            Instruction::DIV(dst, src) => write!(f, "div {}, {}", r(dst), r(src)),
+            Instruction::CLR(reg) => write!(f, "clr {}", r(reg)),
+            Instruction::INC(reg) => write!(f, "inc {}", r(reg)),
+            Instruction::DEC(reg) => write!(f, "dec {}", r(reg)),
+            Instruction::PRINT(reg) => write!(f, "print {}", r(reg)),
+            Instruction::POW(dst, value) => write!(f, "pow {}, {}", r(dst), value),
+            Instruction::MOVR(dst, src) => write!(f, "movr {}, {}", r(dst), r(src)),
+            Instruction::CMP(dst, src) => write!(f, "cmp {}, {}", r(dst), r(src)),
+            Instruction::JMP(address) => write!(f, "jmp {}", address),
+            Instruction::JZ(address) => write!(f, "jz {}", address),
+            Instruction::JNZ(address) => write!(f, "jnz {}", address),
+            Instruction::JC(address) => write!(f, "jc {}", address),
+            Instruction::JNC(address) => write!(f, "jnc {}", address),
+            Instruction::JN(address) => write!(f, "jn {}", address),
+            Instruction::JNN(address) => write!(f, "jnn {}", address),
+            Instruction::JO(address) => write!(f, "jo {}", address),
+            Instruction::JNO(address) => write!(f, "jno {}", address),
+            Instruction::JA(address) => write!(f, "ja {}", address),
+            Instruction::JBE(address) => write!(f, "jbe {}", address),
+            Instruction::JL(address) => write!(f, "jl {}", address),
+            Instruction::JGE(address) => write!(f, "jge {}", address),
+            Instruction::JG(address) => write!(f, "jg {}", address),
+            Instruction::JLE(address) => write!(f, "jle {}", address),
+            Instruction::LOAD(reg, address) => write!(f, "load {}, [{}]", r(reg), address),
+            Instruction::LOADX(reg, base, offset) => {
+                write!(f, "load {}, {}", r(reg), indexed_operand(*base, *offset))
+            }
+            Instruction::STORE(address, reg) => write!(f, "store [{}], {}", address, r(reg)),
+            Instruction::STOREX(base, offset, reg) => {
+                write!(f, "store {}, {}", indexed_operand(*base, *offset), r(reg))
+            }
+            Instruction::IMUL(dst, src) => write!(f, "imul {}, {}", r(dst), r(src)),
+            Instruction::IDIV(dst, src) => write!(f, "idiv {}, {}", r(dst), r(src)),
+            Instruction::ICMP(dst, src) => write!(f, "icmp {}, {}", r(dst), r(src)),
+            Instruction::AND(dst, src) => write!(f, "and {}, {}", r(dst), r(src)),
+            Instruction::OR(dst, src) => write!(f, "or {}, {}", r(dst), r(src)),
+            Instruction::XOR(dst, src) => write!(f, "xor {}, {}", r(dst), r(src)),
+            Instruction::NOT(reg) => write!(f, "not {}", r(reg)),
+            Instruction::SHL(reg, count) => write!(f, "shl {}, {}", r(reg), count),
+            Instruction::SHR(reg, count) => write!(f, "shr {}, {}", r(reg), count),
+            Instruction::ROL(reg, count) => write!(f, "rol {}, {}", r(reg), count),
+            Instruction::ROR(reg, count) => write!(f, "ror {}, {}", r(reg), count),
+            Instruction::IN(reg) => write!(f, "in {}", r(reg)),
+            Instruction::PUSH(reg) => write!(f, "push {}", r(reg)),
+            Instruction::POP(reg) => write!(f, "pop {}", r(reg)),
+            Instruction::CALL(address) => write!(f, "call {}", address),
+            Instruction::RET => write!(f, "ret"),
+            Instruction::NOP => write!(f, "nop"),
+            Instruction::HALT => write!(f, "halt"),
+            Instruction::WORD(value) => write!(f, ".word {:#06x}", value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the words an instruction encodes to.
+    fn round_trip(instruction: &Instruction) -> Instruction {
+        let words = CPU::encode_instruction(instruction);
+        assert_eq!(words.len(), instruction_length(words[0]) as usize);
+        decode_instruction(words[0], words.get(1).copied().unwrap_or(0))
+    }
+
+    #[test]
+    fn decodes_every_instruction_it_encodes() {
+        use Instruction::*;
+        let instructions = [
+            ADD(1, 2),
+            MOV(3, 0xFB),
+            MUL(4, 5),
+            SUB(6, 7),
+            SWAP(8, 9),
+            DIV(10, 11),
+            CLR(12),
+            INC(13),
+            DEC(14),
+            PRINT(15),
+            POW(0, 255),
+            MOVR(1, 0),
+            CMP(2, 3),
+            JMP(0),
+            JZ(1),
+            JNZ(2),
+            JC(3),
+            JNC(4),
+            JN(5),
+            JNN(6),
+            JO(7),
+            JNO(8),
+            JA(9),
+            JBE(10),
+            JL(11),
+            JGE(12),
+            JG(13),
+            JLE(254),
+            LOAD(1, 254),
+            LOADX(2, 3, 255),
+            STORE(0, 4),
+            STOREX(5, 0, 6),
+            IMUL(7, 8),
+            IDIV(9, 10),
+            ICMP(11, 12),
+            AND(13, 14),
+            OR(15, 0),
+            XOR(1, 1),
+            NOT(2),
+            SHL(3, 0),
+            SHR(4, 15),
+            ROL(5, 1),
+            ROR(6, 8),
+            IN(7),
+            PUSH(8),
+            POP(9),
+            CALL(200),
+            RET,
+            NOP,
+            HALT,
+        ];
+        for instruction in &instructions {
+            assert_eq!(&round_trip(instruction), instruction);
+        }
+    }
+
+    #[test]
+    fn decodes_what_the_assembler_never_emits_as_words() {
+        let extended = |ext_opcode: u16| (EXT_OPCODE << 12) | ext_opcode;
+        let words = [
+            (0x0001, 0),                              // HALT with a stray bit
+            (ADD_OPCODE << 12 | 0x0121, 0),           // Low nibble of a two-register instruction
+            (JMP_OPCODE << 12 | 0x0F00, 0),           // Unknown jump condition
+            (JMP_OPCODE << 12 | 0x00FF, 0),           // Jump past the end of memory
+            (extended(0x7F), 0),                      // Unknown extended opcode
+            (extended(EXT_RET) | 0x0100, 0),          // RET with a register
+            (extended(EXT_SHL), 16),                  // Shift count the CPU would cut to 0
+            (extended(EXT_ROR), 0x8001),              // Shift count with stray bits
+            (extended(EXT_LOAD), MEMORY_SIZE as u16), // Address past the end of memory
+            (extended(EXT_STORE), 0xFFFF),            // Address that isn't in memory
+            (extended(EXT_CALL), MEMORY_SIZE as u16), // Call past the end of memory
+            (extended(EXT_AND), 0x1001),              // Stray bits after the source register
+            (extended(EXT_LOAD_INDEXED), 0x1100),     // Offset past 8 bits
+        ];
+        for (word, operand) in words {
+            assert_eq!(
+                decode_instruction(word, operand),
+                Instruction::WORD(word),
+                "{:#06x} {:#06x}",
+                word,
+                operand
+            );
+        }
+    }
 }
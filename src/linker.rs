@@ -0,0 +1,371 @@
+use crate::*;
+use std::collections::HashMap;
+use std::fs;
+
+// Every object file starts with these bytes
+pub const OBJECT_MAGIC: [u8; 4] = *b"ALIO";
+
+const NO_SYMBOL: u16 = 0xFFFF; // Symbol index of a relocation to an address in the same object
+
+/// Which part of a word holds an address that needs relocating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    Low8, // The low 8 bits, like the address of a jump
+    Word, // The whole word, like the operand of a CALL, LOAD or STORE
+}
+
+impl RelocationKind {
+    fn to_u16(self) -> u16 {
+        match self {
+            RelocationKind::Low8 => 0,
+            RelocationKind::Word => 1,
+        }
+    }
+
+    fn from_u16(kind: u16) -> Option<RelocationKind> {
+        match kind {
+            0 => Some(RelocationKind::Low8),
+            1 => Some(RelocationKind::Word),
+            _ => None,
+        }
+    }
+}
+
+/// An address in an object that depends on where things end up once linked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub address: u16, // Address of the word to patch, relative to the start of the object
+    pub kind: RelocationKind,
+    pub symbol: Option<String>, // Imported symbol, or None for an address in the same object
+}
+
+/// An assembled file that can be linked with others. Its addresses start at 0, and every
+/// address that depends on where the object or an imported symbol ends up has a relocation.
+/// Every number in the file is a little-endian u16, and names are a length followed by bytes:
+///
+/// ```text
+/// "ALIO" version length word*length
+/// export_count (name address)*export_count
+/// import_count name*import_count
+/// relocation_count (address kind symbol)*relocation_count
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub words: Vec<u16>,
+    pub exports: Vec<(String, u16)>, // Exported symbols and their addresses
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    pub fn from_program(program: &Program) -> Object {
+        let relocations = program
+            .relocations
+            .iter()
+            .map(|(address, kind, label)| Relocation {
+                address: *address,
+                kind: *kind,
+                symbol: program.imports.contains(label).then(|| label.clone()),
+            })
+            .collect();
+        Object {
+            words: program
+                .instructions
+                .iter()
+                .flat_map(CPU::encode_instruction)
+                .collect(),
+            exports: program
+                .exports
+                .iter()
+                .map(|name| (name.clone(), program.labels[name]))
+                .collect(),
+            imports: program.imports.clone(),
+            relocations,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = OBJECT_MAGIC.to_vec();
+        let mut push = |value: u16| bytes.extend(value.to_le_bytes());
+        push(ISA_VERSION);
+        push(self.words.len() as u16);
+        for &word in &self.words {
+            push(word);
+        }
+        let push_name = |bytes: &mut Vec<u8>, name: &str| {
+            bytes.extend((name.len() as u16).to_le_bytes());
+            bytes.extend(name.as_bytes());
+        };
+        bytes.extend((self.exports.len() as u16).to_le_bytes());
+        for (name, address) in &self.exports {
+            push_name(&mut bytes, name);
+            bytes.extend(address.to_le_bytes());
+        }
+        bytes.extend((self.imports.len() as u16).to_le_bytes());
+        for name in &self.imports {
+            push_name(&mut bytes, name);
+        }
+        bytes.extend((self.relocations.len() as u16).to_le_bytes());
+        for relocation in &self.relocations {
+            let symbol = relocation
+                .symbol
+                .as_ref()
+                .and_then(|symbol| self.imports.iter().position(|name| name == symbol))
+                .map_or(NO_SYMBOL, |index| index as u16);
+            for value in [relocation.address, relocation.kind.to_u16(), symbol] {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, BinaryError> {
+        if !bytes.starts_with(&OBJECT_MAGIC) {
+            return Err(BinaryError::NotABinary);
+        }
+        let mut reader = Reader {
+            bytes,
+            offset: OBJECT_MAGIC.len(),
+        };
+
+        let version = reader.u16()?;
+        if version != ISA_VERSION {
+            return Err(BinaryError::UnsupportedVersion(version));
+        }
+        let length = reader.u16()?;
+        let words = (0..length)
+            .map(|_| reader.u16())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut exports = Vec::new();
+        for _ in 0..reader.u16()? {
+            let name = reader.name()?;
+            let address = reader.u16()?;
+            if address as usize >= words.len() {
+                return Err(BinaryError::InvalidSymbol);
+            }
+            exports.push((name, address));
+        }
+        let imports = (0..reader.u16()?)
+            .map(|_| reader.name())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut relocations = Vec::new();
+        for _ in 0..reader.u16()? {
+            let address = reader.u16()?;
+            let kind = reader.u16()?;
+            let kind =
+                RelocationKind::from_u16(kind).ok_or(BinaryError::UnknownRelocation(kind))?;
+            let symbol = match reader.u16()? {
+                NO_SYMBOL => None,
+                index => Some(
+                    imports
+                        .get(index as usize)
+                        .ok_or(BinaryError::InvalidSymbol)?
+                        .clone(),
+                ),
+            };
+            if address as usize >= words.len() {
+                return Err(BinaryError::RelocationOutOfRange(address));
+            }
+            relocations.push(Relocation {
+                address,
+                kind,
+                symbol,
+            });
+        }
+
+        let rest = bytes.len() - reader.offset;
+        if rest > 0 {
+            return Err(BinaryError::TrailingBytes(rest));
+        }
+        Ok(Object {
+            words,
+            exports,
+            imports,
+            relocations,
+        })
+    }
+
+    pub fn write(&self, filename: &str) -> Result<(), Error> {
+        fs::write(filename, self.to_bytes())
+            .map_err(|e| Error::Io(format!("Error writing object file '{}'", filename), e))
+    }
+
+    /// Reads an object file, or assembles the file if it is assembly instead.
+    pub fn load(filename: &str) -> Result<Object, Error> {
+        let bytes = fs::read(filename)
+            .map_err(|e| Error::Io(format!("Error reading file '{}'", filename), e))?;
+        if bytes.starts_with(&OBJECT_MAGIC) {
+            return Object::from_bytes(&bytes).map_err(|e| {
+                Error::Binary(format!("Error loading object file '{}'", filename), e)
+            });
+        }
+        let program = parse_module(filename, String::from_utf8_lossy(&bytes).into_owned())
+            .map_err(|mut error| {
+                if error.file.is_empty() {
+                    error.file = filename.to_string(); // Unless it's in an included file
+                }
+                error
+            })?;
+        Ok(Object::from_program(&program))
+    }
+}
+
+/// Reads the fields of an object file in order.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], BinaryError> {
+        let taken = self
+            .bytes
+            .get(self.offset..self.offset + length)
+            .ok_or(BinaryError::Truncated)?;
+        self.offset += length;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, BinaryError> {
+        self.take(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+    }
+
+    fn name(&mut self) -> Result<String, BinaryError> {
+        let length = self.u16()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| BinaryError::InvalidSymbol)
+    }
+}
+
+/// Lays the objects out one after another, starting at address 0, and resolves their imports
+/// and relocations into a binary. The first object's code runs first.
+pub fn link(objects: &[(String, Object)]) -> Result<Binary, LinkError> {
+    let mut bases = Vec::new(); // Address each object starts at
+    let mut size = 0;
+    for (_, object) in objects {
+        bases.push(size as u16);
+        size += object.words.len();
+    }
+    if size > MEMORY_SIZE {
+        return Err(LinkError::ProgramTooLarge(size));
+    }
+
+    // Every exported symbol, and the file that exports it
+    let mut symbols: HashMap<&str, (u16, &str)> = HashMap::new();
+    for ((file, object), base) in objects.iter().zip(&bases) {
+        for (name, address) in &object.exports {
+            if let Some((_, other)) = symbols.insert(name, (base + address, file)) {
+                return Err(LinkError::DuplicateSymbol(
+                    name.clone(),
+                    other.to_string(),
+                    file.clone(),
+                ));
+            }
+        }
+    }
+
+    let mut words = Vec::with_capacity(size);
+    for ((file, object), base) in objects.iter().zip(&bases) {
+        let mut code = object.words.clone();
+        for relocation in &object.relocations {
+            // The assembler leaves the address relative to the object, or 0 for imports
+            let target = match &relocation.symbol {
+                None => *base,
+                Some(name) => match symbols.get(name.as_str()) {
+                    Some((address, _)) => *address,
+                    None => return Err(LinkError::UndefinedSymbol(name.clone(), file.clone())),
+                },
+            };
+            let word = &mut code[relocation.address as usize];
+            *word = match relocation.kind {
+                RelocationKind::Low8 => (*word & 0xFF00) | (word.wrapping_add(target) & 0xFF),
+                RelocationKind::Word => word.wrapping_add(target),
+            };
+        }
+        words.extend(code);
+    }
+
+    Ok(Binary {
+        entry: 0,
+        sections: vec![Section {
+            kind: SectionKind::Code,
+            address: 0,
+            words,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(path: &str, source: &str) -> (String, Object) {
+        let program = parse_module(path, source.to_string()).expect("module assembles");
+        (path.to_string(), Object::from_program(&program))
+    }
+
+    #[test]
+    fn relocates_indexed_offsets_and_immediates() {
+        let main = object("main.asm", ".extern get\ncall get\nhalt\n");
+        let table = object(
+            "table.asm",
+            ".global get\n\
+             get: mov bx, 0\n\
+             load dx, [bx + tbl]\n\
+             mov ax, here\n\
+             ret\n\
+             here: nop\n\
+             tbl: .word 7\n",
+        );
+        let binary = link(&[main, table]).expect("links");
+        let words = &binary.sections[0].words;
+
+        // The first file is its CALL and the HALT the assembler ends every file with
+        assert_eq!(words[1], 4);
+        assert_eq!(
+            words[5..7],
+            CPU::encode_instruction(&Instruction::LOADX(3, 1, 11))[..]
+        );
+        assert_eq!(
+            words[7..8],
+            CPU::encode_instruction(&Instruction::MOV(0, 9))[..]
+        );
+    }
+
+    #[test]
+    fn leaves_label_differences_alone() {
+        let main = object("main.asm", "call end\nend: halt\n");
+        let sizes = object(
+            "sizes.asm",
+            "mov ax, end - start\nstart: nop\nnop\nend: ret\n",
+        );
+        let words = &link(&[main, sizes]).expect("links").sections[0].words;
+        assert_eq!(
+            words[4..5],
+            CPU::encode_instruction(&Instruction::MOV(0, 2))[..]
+        );
+
+        // The distance to an imported label depends on where both files end up
+        let error = parse_module(
+            "distance.asm",
+            ".extern f\nmov ax, f - here\nhere: ret\n".to_string(),
+        )
+        .expect_err("the distance isn't known before linking");
+        assert_eq!(
+            error.kind,
+            AsmErrorKind::ImportedLabelDistance("f-here".to_string())
+        );
+        assert_eq!((error.line, error.column), (2, 9));
+    }
+
+    #[test]
+    fn rejects_labels_where_an_address_does_not_fit() {
+        let error = parse_module("shift.asm", "shl ax, here\nhere: ret\n".to_string())
+            .expect_err("a shift count can't hold an address");
+        assert_eq!(
+            error.kind,
+            AsmErrorKind::LabelInImmediate("here".to_string(), 0, 15)
+        );
+    }
+}
@@ -1,135 +1,326 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use colorized::*;
 use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+mod binary;
 mod config;
 mod cpu;
+mod dap;
+mod debugger;
+mod error;
+mod expression;
+mod gdbstub;
 mod helpers;
+mod image;
 mod instructions;
+mod linker;
 mod parser;
+use binary::*;
 use config::*;
 use cpu::*;
+use dap::*;
+use debugger::*;
+use error::*;
+use expression::*;
+use gdbstub::*;
 use helpers::*;
+use image::*;
 use instructions::*;
+use linker::*;
 use parser::*;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Output file for the binary
     #[clap(short = 'o', long)]
     output: Option<String>,
 
+    /// Format of the output file, picked from its extension by default
+    #[clap(long, value_enum)]
+    format: Option<ImageFormat>,
+
     /// Path to the assembly file
-    file: String,
+    #[clap(required = true)]
+    file: Option<String>,
 
     /// Run the binary
     #[clap(short, long)]
     run: bool,
+
+    /// Assemble to an object file for `link` instead of a binary
+    #[clap(short = 'c', long, requires = "output")]
+    object: bool,
+
+    /// How arithmetic overflow is handled, overriding config.toml
+    #[clap(long, value_enum)]
+    arithmetic: Option<ArithmeticMode>,
+
+    /// Directory to look for included files in, before the ones in config.toml
+    #[clap(short = 'I', long = "include", global = true)]
+    include_paths: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Step through an assembly file interactively
+    Debug {
+        /// Path to the assembly file
+        file: String,
+
+        /// How arithmetic overflow is handled, overriding config.toml
+        #[clap(long, value_enum)]
+        arithmetic: Option<ArithmeticMode>,
+    },
+
+    /// Link assembly files and object files into one binary
+    Link {
+        /// Assembly or object files, the first one's code runs first
+        #[clap(required = true)]
+        files: Vec<String>,
+
+        /// Output file for the binary
+        #[clap(short = 'o', long)]
+        output: String,
+
+        /// Format of the output file, picked from its extension by default
+        #[clap(long, value_enum)]
+        format: Option<ImageFormat>,
+    },
+
+    /// Print the instructions in a binary
+    Disasm {
+        /// Path to the binary
+        file: String,
+    },
+
+    /// Run a Debug Adapter Protocol server over stdin and stdout, for editors
+    Dap {
+        /// How arithmetic overflow is handled, unless the launch configuration picks one
+        #[clap(long, value_enum)]
+        arithmetic: Option<ArithmeticMode>,
+    },
+
+    /// Serve an assembly file to a GDB client over the remote serial protocol
+    Gdb {
+        /// Path to the assembly file
+        file: String,
+
+        /// Local TCP port to listen on
+        #[clap(long, default_value_t = 1234)]
+        port: u16,
+
+        /// Listen on a Unix socket at this path instead of a TCP port
+        #[clap(long)]
+        socket: Option<String>,
+
+        /// How arithmetic overflow is handled, overriding config.toml
+        #[clap(long, value_enum)]
+        arithmetic: Option<ArithmeticMode>,
+    },
 }
+// Set by modes that use stdout for something else, like the DAP server
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Turns off the debug output from config.toml everywhere, so it can't mix with other output.
+pub fn silence_debug_output() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+// Include paths given on the command line
+static INCLUDE_PATHS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Adds include paths that are searched before the ones in config.toml.
+pub fn add_include_paths(paths: &[String]) {
+    INCLUDE_PATHS.lock().unwrap().extend_from_slice(paths);
+}
+
 // declare config in config.rs
 pub fn declare_config() -> Config {
-    let config_content = match fs::read_to_string("config.toml") {
-        Ok(content) => content,
-        Err(_) => {
-            return Config::default();
-        }
+    let quiet = QUIET.load(Ordering::Relaxed);
+    let mut config = match fs::read_to_string("config.toml") {
+        Ok(config_content) => match toml::de::from_str::<Config>(&config_content) {
+            Ok(config) => config,
+            Err(_) => {
+                if !quiet {
+                    println!("config.toml parsing failed. defaulting.");
+                }
+                Config::default() // return default config if parsing fails
+            }
+        },
+        Err(_) => Config::default(),
     };
 
-    match toml::de::from_str::<Config>(&config_content) {
-        Ok(config) => config,
-        Err(_) => {
-            println!("config.toml parsing failed. defaulting.");
-            Config::default() // return default config if parsing fails
-        }
+    if quiet {
+        config.debug = false;
+        config.verbose_debug = false;
     }
+    let mut include_paths = INCLUDE_PATHS.lock().unwrap().clone();
+    include_paths.append(&mut config.include_paths);
+    config.include_paths = include_paths;
+    config
 }
 
 fn main() {
-    let config = declare_config();
-    let mut cpu = CPU::new();
-
     // Parse command-line arguments
     let args = Args::parse();
 
+    if let Some(Command::Dap { .. }) = args.command {
+        silence_debug_output(); // Stdout carries the protocol
+    }
+    add_include_paths(&args.include_paths);
+    let config = declare_config();
+
+    if let Err(error) = run(args, &config) {
+        err_print(error.to_string());
+        process::exit(error.exit_code());
+    }
+}
+
+fn run(args: Args, config: &Config) -> Result<(), Error> {
+    match args.command {
+        Some(Command::Debug { file, arithmetic }) => {
+            let mut debugger = Debugger::new(
+                &file,
+                read_file(&file)?,
+                arithmetic.unwrap_or(config.arithmetic),
+            )?;
+            debugger.run();
+            return Ok(());
+        }
+        Some(Command::Link {
+            files,
+            output,
+            format,
+        }) => {
+            let objects = files
+                .into_iter()
+                .map(|file| Object::load(&file).map(|object| (file, object)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let format = format.unwrap_or_else(|| ImageFormat::from_extension(&output));
+            link(&objects)?.write(&output, format)?;
+            println!("Binary emitted to {}", output);
+            return Ok(());
+        }
+        Some(Command::Disasm { file }) => {
+            Binary::read(&file)?.disassemble();
+            return Ok(());
+        }
+        Some(Command::Dap { arithmetic }) => {
+            return DapServer::new(arithmetic.unwrap_or(config.arithmetic))
+                .run()
+                .map_err(|e| Error::Io("Error talking to the editor".to_string(), e));
+        }
+        Some(Command::Gdb {
+            file,
+            port,
+            socket,
+            arithmetic,
+        }) => {
+            let program = parse_file(&file, read_file(&file)?)?;
+            let arithmetic = arithmetic.unwrap_or(config.arithmetic);
+            return match socket {
+                #[cfg(unix)]
+                Some(path) => serve_unix(program, arithmetic, &path),
+                #[cfg(not(unix))]
+                Some(_) => Err(Error::Io(
+                    "Error listening on a Unix socket".to_string(),
+                    std::io::Error::from(std::io::ErrorKind::Unsupported),
+                )),
+                None => serve_tcp(program, arithmetic, port),
+            };
+        }
+        None => {}
+    }
+
+    let file = args.file.unwrap_or_default(); // Required by clap when there is no subcommand
+    let mut cpu = CPU::new();
+    cpu.arithmetic = args.arithmetic.unwrap_or(config.arithmetic);
+
     // Check if the -o flag is used for compilation
     if let Some(output_file) = args.output {
+        if args.object {
+            Object::from_program(&parse_module(&file, read_file(&file)?)?).write(&output_file)?;
+            println!("Object emitted to {}", output_file);
+            return Ok(());
+        }
+
         // Read the assembly file
-        let program = parse_file(read_file(&args.file));
+        let program = parse_file(&file, read_file(&file)?)?;
 
         if config.verbose_debug {
             println!("{:?}", program);
         }
 
-        // Load the program into the CPU
-        cpu.load_program(&program);
-
         // Emit the binary
-        if let Err(e) = cpu.emit_binary(&output_file) {
-            eprintln!("Error writing binary file: {}", e);
-            return;
-        } else {
-            println!("Binary emitted to {}", output_file);
-        }
+        let format = args
+            .format
+            .unwrap_or_else(|| ImageFormat::from_extension(&output_file));
+        Binary::from_program(&program).write(&output_file, format)?;
+        println!("Binary emitted to {}", output_file);
 
-        return; // Exit after compiling
+        return Ok(()); // Exit after compiling
     }
 
     // If the -r flag is used, run the specified file
     if args.run {
-        let file_to_run = &args.file; // Use the provided file argument
+        let file_to_run = &file; // Use the provided file argument
 
         // Attempt to load the binary file
-        if let Err(e) = cpu.load_binary(file_to_run) {
-            eprintln!("Error loading binary file: {}", e);
-
-            // If loading the binary fails, assume it's an assembly file and compile it
-            let program = parse_file(read_file(&file_to_run.to_string()));
+        match cpu.load_binary(file_to_run) {
+            Ok(()) => {}
+            Err(Error::Binary(_, BinaryError::NotABinary)) => {
+                eprintln!("{} is not a binary, assembling it", file_to_run);
 
-            if config.verbose_debug {
-                println!("{:?}", program);
-            }
+                // Without the magic bytes, assume it's an assembly file and compile it
+                let program = parse_file(file_to_run, read_file(&file_to_run.to_string())?)?;
 
-            // Load the program into the CPU
-            cpu.load_program(&program);
+                if config.verbose_debug {
+                    println!("{:?}", program);
+                }
 
-            // Emit default output file if not specified
-            let output_file = format!("{}.bin", file_to_run);
-            if let Err(e) = cpu.emit_binary(&output_file) {
-                eprintln!("Error writing binary file: {}", e);
-                return;
-            } else {
+                // Emit default output file if not specified
+                let output_file = format!("{}.bin", file_to_run);
+                Binary::from_program(&program).write(&output_file, ImageFormat::Binary)?;
                 println!("Binary emitted to {}", output_file);
-            }
 
-            // Run the newly created binary
-            if let Err(e) = cpu.load_binary(&output_file) {
-                eprintln!("Error loading binary file: {}", e);
-                return;
+                // Run the newly created binary
+                cpu.load_binary(&output_file)?;
+                cpu.run()?;
+                return Ok(()); // Exit after running the binary
             }
-            cpu.run();
-            return; // Exit after running the binary
+            Err(error) => return Err(error), // A broken binary shouldn't be read as assembly
         }
 
         // If it successfully loads the binary, just run it
-        cpu.run();
-        return; // Exit after running the binary
+        cpu.run()?;
+        return Ok(()); // Exit after running the binary
     }
 
     // Normal execution flow for assembly if no run flag is used
-    let program = parse_file(read_file(&args.file));
+    let source = read_file(&file)?;
+    let program = parse_file(&file, source.clone())?;
 
     if config.verbose_debug {
         println!("{:?}", program);
     }
 
-    // Load the program into the CPU and run it
+    // Load the program into the CPU and run it, keeping any fault until the registers are shown
     cpu.load_program(&program);
-    cpu.run();
+    let result = cpu.run();
 
     // Print register values if debug is enabled
     if config.debug || config.verbose_debug {
         for (i, &value) in cpu.registers.iter().enumerate() {
-            println!("R{}: {}", i, value.to_string().color(Colors::CyanFg)); // Print out registers
+            println!("R{}: {}", i, cpu.format_value(value).color(Colors::CyanFg));
+            // Print out registers
         }
+        println!("FLAGS: {}", format_flags(&cpu).color(Colors::CyanFg));
     }
 
     // Print file contents if debug is enabled
@@ -137,7 +328,9 @@ fn main() {
         println!(
             "{}\n{}\n",
             "\nFILE CONTENTS".color(Colors::WhiteFg),
-            read_file(&args.file).color(Colors::GreenFg)
+            source.color(Colors::GreenFg)
         );
     }
+
+    Ok(result?)
 }
@@ -1,203 +1,1709 @@
 use crate::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Reads the contents of a file or creates it with default content.
-pub fn read_file(f_name: &String) -> String {
+pub fn read_file(f_name: &String) -> Result<String, Error> {
     // Check if the file exists at the given path
     if Path::new(&f_name).exists() {
         // If it exists, read the contents of the file
-        fs::read_to_string(f_name).unwrap_or_else(|_| {
-            // If reading fails, print an error and exit
-            println!("Error reading file '{}'. Exiting.", f_name);
-            std::process::exit(1);
-        })
+        fs::read_to_string(f_name)
+            .map_err(|error| Error::Io(format!("Error reading file '{}'", f_name), error))
     } else {
         // If the file does not exist, create it with default content
         println!("Could not find file; creating it.");
         let default_content = "MOV 1, 5\nMOV 2, 3\nADD 0, 1\nSUB 1, 2\nMUL 1, 2";
-        fs::write(f_name, default_content).unwrap_or_else(|_| {
-            // If writing fails, print an error and exit
-            println!("Could not write to file '{}'. Exiting.", f_name);
-            std::process::exit(1);
-        });
+        fs::write(f_name, default_content)
+            .map_err(|error| Error::Io(format!("Could not write to file '{}'", f_name), error))?;
         // Return the default content as a string
-        default_content.to_string()
+        Ok(default_content.to_string())
+    }
+}
+
+/// The kinds of tokens a line is made of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    Word,         // A mnemonic, register, label, constant or number, like `mov`, `ax` or `0x1F`
+    Label,        // A label being defined, like `loop:`, without its colon
+    Directive,    // Like `.equ`, or the name of a function, like `.square`
+    Comma,        // Between operands
+    OpenBracket,  // The `[` of a memory operand
+    CloseBracket, // The `]` of a memory operand
+    Operator,     // Part of an expression, like `+` or `(`
+    String,       // A "string", with its quotes and escapes
+    Char,         // A 'c' character, with its quotes and escapes
+}
+
+/// A token, along with where it starts on its line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    column: usize, // Starting at 1, or 0 for tokens the assembler adds
+}
+
+impl Token {
+    /// A token the assembler adds, like the RET at the end of a function.
+    fn generated(kind: TokenKind, text: &str) -> Token {
+        Token {
+            kind,
+            text: text.to_string(),
+            column: 0,
+        }
+    }
+}
+
+/// A tokenized line, along with where it came from in the source.
+#[derive(Debug, Clone)]
+struct Line {
+    number: usize, // Line number in its file, or of the macro call for lines expanded from a macro
+    file: String,  // The file the line is in, empty for the file being assembled
+    text: String,  // The line itself, for errors
+    main_line: usize, // Line of the file being assembled it came from, through any `.include`s
+    tokens: Vec<Token>,
+    macros: Vec<MacroFrame>, // Macros the line was expanded from, innermost first
+}
+
+impl Line {
+    /// Creates an error on this line.
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        self.locate(AsmError::new(kind, self.number))
+    }
+
+    /// Creates an error on this line, pointing at the column it was found at.
+    fn error_at(&self, kind: AsmErrorKind, column: usize) -> AsmError {
+        let mut error = self.error(kind);
+        error.column = column;
+        error
+    }
+
+    /// Points an error found on this line at the file, line and macros it came from.
+    fn locate(&self, mut error: AsmError) -> AsmError {
+        error.line = self.number;
+        error.file = self.file.clone();
+        error.text = self.text.clone();
+        error.macros = self.macros.clone().into();
+        error
+    }
+
+    /// A line the assembler adds, like the RET at the end of a function.
+    fn generated(&self, tokens: Vec<Token>) -> Line {
+        Line {
+            tokens,
+            ..self.clone()
+        }
     }
 }
 
 /// Lexer to tokenize the assembly code.
-fn lex(input: &str) -> Vec<Vec<String>> {
+fn lex(input: &str, file: &str) -> Result<Vec<Line>, AsmError> {
     input
         .lines() // Split input into lines
-        .map(|line| {
-            // Clean the line by removing comments and splitting it into tokens
-            let clean_line = line.split(';').next().unwrap_or(line); // Ignore comments
-            clean_line
-                .split_whitespace() // Split line into words/tokens based on whitespace
-                .filter(|token| !token.is_empty()) // Ignore empty tokens
-                .map(|token| token.to_string()) // Convert tokens from &str to String
-                .collect() // Collect tokens into a Vec<String>
+        .enumerate()
+        .map(|(index, text)| {
+            let mut line = Line {
+                number: index + 1, // Line numbers start at 1
+                file: file.to_string(),
+                text: text.to_string(),
+                main_line: index + 1,
+                tokens: Vec::new(),
+                macros: Vec::new(),
+            };
+            line.tokens =
+                tokenize_line(text).map_err(|(kind, column)| line.error_at(kind, column))?;
+            Ok(line)
         })
-        .collect() // Collect all lines of tokens into a Vec<Vec<String>>
+        .collect() // Collect all lines of tokens into a Vec<Line>
 }
 
-/// Parses the tokenized lines into instructions, handling functions internally.
-pub fn parse_file(f_contents: String) -> Vec<Instruction> {
-    let mut instructions = Vec::new(); // Vector to store parsed instructions
-    let mut functions = HashMap::new(); // Map to store functions and their instructions
-    let config = declare_config(); // Obtain configuration settings
-    let tokens = lex(&f_contents); // Tokenize the input contents
-    let mut current_function: Option<String> = None; // Track the current function being defined
-    let mut current_function_instructions = Vec::new(); // Store instructions for the current function
+/// Checks if a character can be part of a name or number.
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
 
-    if config.verbose_debug {
-        // If verbose debugging is enabled, print the tokenized instructions
-        println!("Tokenized instructions:\n{:?}", tokens);
+/// Splits a line into tokens, ignoring its comment, which starts with `;` or `#`. Errors come
+/// with the column they were found at.
+fn tokenize_line(text: &str) -> Result<Vec<Token>, (AsmErrorKind, usize)> {
+    let chars: Vec<char> = text.chars().collect();
+    let word_end = |start: usize| {
+        (start..chars.len())
+            .find(|&i| !is_word_char(chars[i]))
+            .unwrap_or(chars.len())
+    };
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while let Some(&c) = chars.get(start) {
+        let next = chars.get(start + 1).copied();
+        let (kind, end) = match c {
+            ';' | '#' => break, // Ignore comments
+            _ if c.is_whitespace() => {
+                start += 1;
+                continue;
+            }
+            ',' => (TokenKind::Comma, start + 1),
+            '[' => (TokenKind::OpenBracket, start + 1),
+            ']' => (TokenKind::CloseBracket, start + 1),
+            '"' | '\'' => {
+                let Some(end) = closing_quote(&chars, start) else {
+                    let rest: String = chars[start..].iter().collect();
+                    let kind = match c {
+                        '"' => AsmErrorKind::InvalidString(rest),
+                        _ => AsmErrorKind::InvalidCharacter(rest),
+                    };
+                    return Err((kind, start + 1));
+                };
+                match c {
+                    '"' => (TokenKind::String, end + 1),
+                    _ => (TokenKind::Char, end + 1),
+                }
+            }
+            '.' if next.is_some_and(is_word_char) => (TokenKind::Directive, word_end(start + 1)),
+            _ if is_word_char(c) => match word_end(start) {
+                end if chars.get(end) == Some(&':') => {
+                    tokens.push(Token {
+                        kind: TokenKind::Label,
+                        text: chars[start..end].iter().collect(),
+                        column: start + 1,
+                    });
+                    start = end + 1; // Past the colon
+                    continue;
+                }
+                end => (TokenKind::Word, end),
+            },
+            '<' | '>' if next == Some(c) => (TokenKind::Operator, start + 2), // Shifts
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' | '(' | ')' => {
+                (TokenKind::Operator, start + 1)
+            }
+            _ => return Err((AsmErrorKind::UnexpectedCharacter(c), start + 1)),
+        };
+        tokens.push(Token {
+            kind,
+            text: chars[start..end].iter().collect(),
+            column: start + 1,
+        });
+        start = end;
+    }
+    Ok(tokens)
+}
+
+/// Finds the quote that ends a string or character starting at `start`, skipping escaped ones.
+fn closing_quote(chars: &[char], start: usize) -> Option<usize> {
+    let mut escaped = false;
+    for (i, &c) in chars.iter().enumerate().skip(start + 1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == chars[start] => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Puts tokens back together as text, for expressions and errors. Only names, numbers and
+/// literals need a space between them.
+fn join(tokens: &[Token]) -> String {
+    let spaced = |token: &Token| {
+        matches!(
+            token.kind,
+            TokenKind::Word | TokenKind::Directive | TokenKind::String | TokenKind::Char
+        )
+    };
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if let Some(previous) = i.checked_sub(1).map(|i| &tokens[i]) {
+            if previous.kind == TokenKind::Comma || (spaced(previous) && spaced(token)) {
+                text.push(' ');
+            }
+        }
+        text += &token.text;
+    }
+    text
+}
+
+/// The directive a line starts with, if any.
+fn directive(tokens: &[Token]) -> Option<&str> {
+    tokens
+        .first()
+        .filter(|token| token.kind == TokenKind::Directive)
+        .map(|token| token.text.as_str())
+}
+
+/// Skips the comma that can come after the name of an instruction or macro, like in
+/// `INSTRUCTION, DESTINATION, SOURCE`.
+fn after_name(tokens: &[Token]) -> &[Token] {
+    match tokens.first() {
+        Some(token) if token.kind == TokenKind::Comma => &tokens[1..],
+        _ => tokens,
+    }
+}
+
+/// Splits tokens into operands at their commas.
+fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    tokens
+        .split(|token| token.kind == TokenKind::Comma)
+        .collect()
+}
+
+/// Splits tokens into operands at their commas, checking that none of them are empty, like the
+/// second one in `mov ax,`.
+fn operands<'a>(line: &Line, tokens: &'a [Token]) -> Result<Vec<&'a [Token]>, AsmError> {
+    let is_comma = |token: &&Token| token.kind == TokenKind::Comma;
+    let stray_comma = tokens
+        .first()
+        .filter(is_comma)
+        .or_else(|| {
+            tokens
+                .windows(2)
+                .find(|pair| is_comma(&&pair[0]) && is_comma(&&pair[1]))
+                .map(|pair| &pair[1])
+        })
+        .or_else(|| tokens.last().filter(is_comma));
+    if let Some(comma) = stray_comma {
+        return Err(line.error_at(AsmErrorKind::MissingOperand, comma.column));
+    }
+    Ok(split_operands(tokens))
+}
+
+/// Files that are being included, or already have been.
+struct Includes {
+    paths: Vec<PathBuf>,        // Directories searched after the including file's own
+    stack: Vec<PathBuf>,        // Files being included right now, to catch cycles
+    included: HashSet<PathBuf>, // Every file included so far, since a file is only included once
+}
+
+/// Replaces every `.include "file"` line with the lines of that file. The file is looked for
+/// next to the file including it first, then in each include path.
+fn include_files(
+    lines: Vec<Line>,
+    dir: &Path,
+    includes: &mut Includes,
+) -> Result<Vec<Line>, AsmError> {
+    let mut result = Vec::new();
+    for line in lines {
+        if directive(&line.tokens) != Some(".include") {
+            result.push(line);
+            continue;
+        }
+        let name = parse_string(&line, &line.tokens[1..])?;
+        let name = String::from_utf8_lossy(&name);
+        let name = name.as_ref();
+        if name.is_empty() {
+            return Err(line.error(AsmErrorKind::MissingOperand));
+        }
+
+        let path = std::iter::once(dir.join(name))
+            .chain(
+                includes
+                    .paths
+                    .iter()
+                    .map(|include_path| include_path.join(name)),
+            )
+            .find(|path| path.is_file())
+            .ok_or_else(|| line.error(AsmErrorKind::IncludeNotFound(name.to_string())))?;
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if includes.stack.contains(&canonical) {
+            return Err(line.error(AsmErrorKind::IncludeCycle(name.to_string())));
+        }
+        if !includes.included.insert(canonical.clone()) {
+            continue; // Already included
+        }
+        let contents = fs::read_to_string(&path)
+            .map_err(|error| line.error(AsmErrorKind::IncludeFailed(error.to_string())))?;
+
+        let mut file_lines = lex(&contents, &path.to_string_lossy())?;
+        for file_line in &mut file_lines {
+            file_line.main_line = line.main_line; // Shown at the .include in debuggers
+        }
+        includes.stack.push(canonical);
+        let file_dir = path.parent().unwrap_or(Path::new("."));
+        result.extend(include_files(file_lines, file_dir, includes)?);
+        includes.stack.pop();
+    }
+    Ok(result)
+}
+
+// How deep macros can expand inside each other, to catch macros that expand themselves
+pub const MAX_MACRO_DEPTH: usize = 64;
+
+/// A `.macro name params ... .endm` block.
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>,
+}
+
+/// Takes the macro definitions out of the program, then expands every use of a macro into its
+/// body. Labels defined inside a macro get a unique name for each expansion, so a macro can be
+/// used more than once.
+fn expand_macros(lines: Vec<Line>) -> Result<Vec<Line>, AsmError> {
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut program = Vec::new();
+    let mut current: Option<(String, Line, Macro)> = None; // Name, .macro line and the macro so far
+
+    for line in lines {
+        match directive(&line.tokens) {
+            Some(".macro") => {
+                if current.is_some() {
+                    return Err(line.error(AsmErrorKind::NestedMacro));
+                }
+                let Some(name) = line.tokens.get(1) else {
+                    return Err(line.error(AsmErrorKind::MissingOperand));
+                };
+                if name.kind != TokenKind::Word || !is_valid_label(&name.text) {
+                    return Err(
+                        line.error_at(AsmErrorKind::InvalidLabel(name.text.clone()), name.column)
+                    );
+                }
+                let mut params = Vec::new();
+                for param in operands(&line, after_name(&line.tokens[2..]))? {
+                    match param {
+                        [param] if param.kind == TokenKind::Word && is_valid_label(&param.text) => {
+                            params.push(param.text.clone())
+                        }
+                        _ => {
+                            return Err(line.error_at(
+                                AsmErrorKind::InvalidLabel(join(param)),
+                                param[0].column,
+                            ))
+                        }
+                    }
+                }
+                let definition = Macro {
+                    params,
+                    body: Vec::new(),
+                };
+                current = Some((name.text.clone(), line, definition));
+            }
+            Some(".endm") => match current.take() {
+                Some((name, start, definition)) => {
+                    if macros.insert(name.clone(), definition).is_some() {
+                        return Err(start.error(AsmErrorKind::DuplicateMacro(name)));
+                    }
+                }
+                None => return Err(line.error(AsmErrorKind::EndmWithoutMacro)),
+            },
+            _ => match &mut current {
+                Some((_, _, definition)) => definition.body.push(line),
+                None => program.push(line),
+            },
+        }
+    }
+    if let Some((name, start, _)) = current {
+        return Err(start.error(AsmErrorKind::MissingEndm(name)));
+    }
+
+    let mut expanded = Vec::new();
+    let mut expansions = 0; // Number of expansions so far, for naming labels inside macros
+    for line in program {
+        expand_line(line, &macros, &mut expansions, &mut expanded)?;
+    }
+    Ok(expanded)
+}
+
+/// Expands a line if it uses a macro, along with any macros used inside the macro.
+fn expand_line(
+    line: Line,
+    macros: &HashMap<String, Macro>,
+    expansions: &mut usize,
+    expanded: &mut Vec<Line>,
+) -> Result<(), AsmError> {
+    let (label, rest) = split_label(&line.tokens);
+    let Some((name, definition)) = rest
+        .first()
+        .filter(|name| name.kind == TokenKind::Word)
+        .and_then(|name| macros.get_key_value(name.text.as_str()))
+    else {
+        expanded.push(line);
+        return Ok(());
+    };
+    if line.macros.len() >= MAX_MACRO_DEPTH {
+        let mut error = line.error(AsmErrorKind::RecursiveMacro(name.clone()));
+        error.macros = error.macros[..1].into(); // The rest are the same macro over and over
+        return Err(error);
+    }
+
+    let args = operands(&line, after_name(&rest[1..]))?;
+    if args.len() != definition.params.len() {
+        return Err(line.error(AsmErrorKind::WrongArgumentCount(
+            name.clone(),
+            definition.params.len(),
+            args.len(),
+        )));
+    }
+    if let Some(label) = label {
+        // Labels the first line
+        expanded.push(line.generated(vec![Token::generated(TokenKind::Label, label)]));
+    }
+
+    // Parameters are replaced by the arguments, and labels by a name unique to this expansion
+    *expansions += 1;
+    let mut names: HashMap<&str, Vec<Token>> = definition
+        .params
+        .iter()
+        .map(String::as_str)
+        .zip(args.iter().map(|arg| arg.to_vec()))
+        .collect();
+    for body_line in &definition.body {
+        match split_label(&body_line.tokens) {
+            (Some(label), _) if names.contains_key(label) => {} // Named by an argument
+            (Some(label), _) => {
+                let unique = format!("__{}_{}_{}", name, expansions, label);
+                names.insert(label, vec![Token::generated(TokenKind::Word, &unique)]);
+            }
+            _ => {}
+        }
+    }
+
+    for body_line in &definition.body {
+        let mut macros_used = vec![MacroFrame {
+            name: name.clone(),
+            line: body_line.number,
+            file: body_line.file.clone(),
+            text: body_line.text.clone(),
+        }];
+        macros_used.extend(line.macros.iter().cloned());
+        let body_line = line.generated(substitute(&body_line.tokens, &names));
+        let body_line = Line {
+            macros: macros_used,
+            ..body_line
+        };
+        expand_line(body_line, macros, expansions, expanded)?;
+    }
+    Ok(())
+}
+
+/// Replaces the parameters and labels of a macro in a line of its body. Strings and characters
+/// are single tokens, so the names inside them are left alone.
+fn substitute(tokens: &[Token], names: &HashMap<&str, Vec<Token>>) -> Vec<Token> {
+    let mut result = Vec::new();
+    for token in tokens {
+        match (token.kind, names.get(token.text.as_str())) {
+            (TokenKind::Word, Some(replacement)) => result.extend(replacement.iter().cloned()),
+            (TokenKind::Label, Some(replacement)) => result.push(Token {
+                text: join(replacement),
+                ..token.clone()
+            }),
+            _ => result.push(token.clone()),
+        }
+    }
+    result
+}
+
+/// Splits a leading `name:` label off a tokenized line.
+fn split_label(tokens: &[Token]) -> (Option<&str>, &[Token]) {
+    match tokens.first() {
+        Some(token) if token.kind == TokenKind::Label => (Some(&token.text), &tokens[1..]),
+        _ => (None, tokens),
+    }
+}
+
+/// Checks that a label is a plain identifier, so it can't be mistaken for a number.
+fn is_valid_label(label: &str) -> bool {
+    let mut chars = label.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Number of words an instruction takes up, which is known from its mnemonic alone.
+fn instruction_size(mnemonic: &str) -> u16 {
+    match mnemonic.to_uppercase().as_str() {
+        "LOAD" | "STORE" | "CALL" | "IMUL" | "IDIV" | "ICMP" => 2,
+        "AND" | "OR" | "XOR" | "SHL" | "SHR" | "ROL" | "ROR" => 2,
+        _ => 1,
+    }
+}
+
+// Directives that put data into the program instead of an instruction
+const DATA_DIRECTIVES: [&str; 6] = [".word", ".byte", ".string", ".fill", ".zero", ".align"];
+
+/// Checks if the tokens after a line's label are a data directive.
+fn is_data(tokens: &[Token]) -> bool {
+    directive(tokens).is_some_and(|directive| DATA_DIRECTIVES.contains(&directive))
+}
+
+/// Splits the operands of a data directive at commas, checking there are no more than `max`.
+fn data_values<'a>(
+    line: &Line,
+    args: &'a [Token],
+    max: usize,
+) -> Result<Vec<&'a [Token]>, AsmError> {
+    let values = operands(line, args)?;
+    if values.is_empty() {
+        return Err(line.error(AsmErrorKind::MissingOperand));
+    }
+    match values.get(max) {
+        Some(extra) => Err(line.error_at(
+            AsmErrorKind::UnexpectedOperand(join(extra)),
+            extra[0].column,
+        )),
+        None => Ok(values),
+    }
+}
+
+/// Reads a quoted string, turning its escapes into the bytes they stand for.
+fn parse_string(line: &Line, args: &[Token]) -> Result<Vec<u8>, AsmError> {
+    let token = match operands(line, args)?.as_slice() {
+        [] => return Err(line.error(AsmErrorKind::MissingOperand)),
+        [[token]] if token.kind == TokenKind::String => token,
+        [value] => {
+            return Err(line.error_at(AsmErrorKind::InvalidString(join(value)), value[0].column))
+        }
+        [_, extra, ..] => {
+            return Err(line.error_at(
+                AsmErrorKind::UnexpectedOperand(join(extra)),
+                extra[0].column,
+            ))
+        }
+    };
+
+    // The lexer only makes string tokens that start and end with a quote
+    let inner = &token.text[1..token.text.len() - 1];
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                bytes.push(unescape(&mut chars).map_err(|kind| line.error_at(kind, token.column))?)
+            }
+            _ => bytes.extend(c.to_string().bytes()), // Stored as UTF-8
+        }
+    }
+    Ok(bytes)
+}
+
+/// Packs bytes two to a word, low byte first, padding the last word with a 0 byte.
+fn pack_bytes(bytes: &[u8]) -> Vec<(u16, Option<String>)> {
+    bytes
+        .chunks(2)
+        .map(|pair| {
+            (
+                u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0)]),
+                None,
+            )
+        })
+        .collect()
+}
+
+/// Evaluates the count of a `.fill`, `.zero` or `.align`. It can only use constants, since the
+/// addresses of labels depend on it.
+fn data_count(
+    line: &Line,
+    value: &[Token],
+    constants: &HashMap<String, i32>,
+    min: i32,
+) -> Result<u16, AsmError> {
+    let no_labels = HashMap::new();
+    let scope = Scope {
+        constants,
+        labels: &no_labels,
+        imports: &[],
+    };
+    let text = join(value);
+    let error = |kind| line.error_at(kind, value[0].column);
+    match evaluate(&text, &scope).map_err(error)?.value {
+        count if (min..=MEMORY_SIZE as i32).contains(&count) => Ok(count as u16),
+        _ => Err(error(AsmErrorKind::ValueOutOfRange(
+            text,
+            min,
+            MEMORY_SIZE as i32,
+        ))),
+    }
+}
+
+/// Number of zero words `.align` adds at an address to reach the next multiple of its operand.
+fn align_padding(
+    line: &Line,
+    args: &[Token],
+    constants: &HashMap<String, i32>,
+    address: usize,
+) -> Result<usize, AsmError> {
+    let alignment = data_count(line, data_values(line, args, 1)?[0], constants, 1)? as usize;
+    Ok((alignment - address % alignment) % alignment)
+}
+
+/// Evaluates a word of data, which can be the address of a label.
+fn data_word(
+    line: &Line,
+    value: &[Token],
+    scope: &Scope,
+) -> Result<(u16, Option<String>), AsmError> {
+    let text = join(value);
+    let error = |kind| line.error_at(kind, value[0].column);
+    match evaluate(&text, scope).map_err(error)? {
+        ExprValue {
+            value: word @ -32768..=65535,
+            label,
+        } => Ok((word as u16, label)),
+        _ => Err(error(AsmErrorKind::ValueOutOfRange(text, -32768, 65535))),
+    }
+}
+
+/// Evaluates a byte of data, which can't be the address of a label.
+fn data_byte(line: &Line, value: &[Token], scope: &Scope) -> Result<u8, AsmError> {
+    let text = join(value);
+    let error = |kind| line.error_at(kind, value[0].column);
+    match evaluate(&text, scope).map_err(error)? {
+        ExprValue { label: Some(_), .. } => Err(error(AsmErrorKind::LabelInByte(text))),
+        ExprValue {
+            value: byte @ -128..=255,
+            ..
+        } => Ok(byte as u8),
+        _ => Err(error(AsmErrorKind::ValueOutOfRange(text, -128, 255))),
+    }
+}
+
+/// Number of words a data directive takes up at an address. This doesn't need the values of
+/// any labels, so it can be worked out before the labels are.
+fn data_size(
+    line: &Line,
+    tokens: &[Token],
+    constants: &HashMap<String, i32>,
+    address: usize,
+) -> Result<usize, AsmError> {
+    let args = &tokens[1..];
+    Ok(match tokens[0].text.as_str() {
+        ".word" => data_values(line, args, usize::MAX)?.len(),
+        ".byte" => data_values(line, args, usize::MAX)?.len().div_ceil(2),
+        ".string" => (parse_string(line, args)?.len() + 1).div_ceil(2), // With its 0 byte
+        ".fill" => data_count(line, data_values(line, args, 2)?[0], constants, 0)? as usize,
+        ".zero" => data_count(line, data_values(line, args, 1)?[0], constants, 0)? as usize,
+        _ => align_padding(line, args, constants, address)?,
+    })
+}
+
+/// Works out the words a data directive at an address puts into the program, along with the
+/// label each word holds the address of, if any. Bytes are packed two to a word, low byte
+/// first, and a `.string` ends in a 0 byte.
+fn parse_data(
+    line: &Line,
+    tokens: &[Token],
+    scope: &Scope,
+    address: usize,
+) -> Result<Vec<(u16, Option<String>)>, AsmError> {
+    let args = &tokens[1..];
+    match tokens[0].text.as_str() {
+        ".word" => data_values(line, args, usize::MAX)?
+            .iter()
+            .map(|value| data_word(line, value, scope))
+            .collect(),
+        ".byte" => {
+            let bytes = data_values(line, args, usize::MAX)?
+                .iter()
+                .map(|value| data_byte(line, value, scope))
+                .collect::<Result<Vec<u8>, _>>()?;
+            Ok(pack_bytes(&bytes))
+        }
+        ".string" => {
+            let mut bytes = parse_string(line, args)?;
+            bytes.push(0);
+            Ok(pack_bytes(&bytes))
+        }
+        ".fill" => {
+            let values = data_values(line, args, 2)?;
+            let count = data_count(line, values[0], scope.constants, 0)?;
+            let word = match values.get(1) {
+                Some(value) => data_word(line, value, scope)?,
+                None => (0, None),
+            };
+            Ok(vec![word; count as usize])
+        }
+        ".zero" => {
+            let count = data_count(line, data_values(line, args, 1)?[0], scope.constants, 0)?;
+            Ok(vec![(0, None); count as usize])
+        }
+        _ => {
+            let padding = align_padding(line, args, scope.constants, address)?;
+            Ok(vec![(0, None); padding])
+        }
+    }
+}
+
+/// Symbols named by `.global` and `.extern` lines, along with the line they were named on.
+#[derive(Debug, Default)]
+struct Symbols {
+    exports: Vec<(String, Line)>,
+    imports: Vec<(String, Line)>,
+}
+
+/// Takes the `.global` and `.extern` lines out of the program, leaving them empty so the
+/// line numbers stay the same.
+fn collect_symbols(lines: &mut [Line]) -> Result<Symbols, AsmError> {
+    let mut symbols = Symbols::default();
+    for line in lines {
+        let list = match directive(&line.tokens) {
+            Some(".global") => &mut symbols.exports,
+            Some(".extern") => &mut symbols.imports,
+            _ => continue,
+        };
+        let names = operands(line, &line.tokens[1..])?;
+        if names.is_empty() {
+            return Err(line.error(AsmErrorKind::MissingOperand));
+        }
+        for name in names {
+            match name {
+                [name] if name.kind == TokenKind::Word && is_valid_label(&name.text) => {
+                    list.push((name.text.clone(), line.clone()))
+                }
+                _ => {
+                    return Err(
+                        line.error_at(AsmErrorKind::InvalidLabel(join(name)), name[0].column)
+                    )
+                }
+            }
+        }
+        line.tokens.clear();
     }
+    Ok(symbols)
+}
 
-    // Iterate over the tokenized lines
-    for (line_number, tokens) in tokens.iter().enumerate() {
-        if tokens.is_empty() {
+/// Takes the `.equ NAME value` lines out of the program and works out the value of each
+/// constant. A constant can use the constants defined before it, but not labels.
+fn collect_constants(lines: &mut [Line]) -> Result<HashMap<String, i32>, AsmError> {
+    let mut constants = HashMap::new();
+    let no_labels = HashMap::new();
+    for line in lines {
+        if directive(&line.tokens) != Some(".equ") {
+            continue;
+        }
+        let Some((name, rest)) = line.tokens[1..].split_first() else {
+            return Err(line.error(AsmErrorKind::MissingOperand));
+        };
+        let value = after_name(rest); // Like `.equ NAME, value`
+        if value.is_empty() {
+            return Err(line.error(AsmErrorKind::MissingOperand));
+        }
+        let invalid = name.kind != TokenKind::Word || !is_valid_label(&name.text);
+        if invalid || register_index(&name.text).is_some() {
+            return Err(line.error_at(AsmErrorKind::InvalidLabel(name.text.clone()), name.column));
+        }
+        let scope = Scope {
+            constants: &constants,
+            labels: &no_labels,
+            imports: &[],
+        };
+        let value = evaluate(&join(value), &scope)
+            .map_err(|kind| line.error_at(kind, value[0].column))?
+            .value;
+        if constants.insert(name.text.clone(), value).is_some() {
+            return Err(line.error_at(AsmErrorKind::DuplicateLabel(name.text.clone()), name.column));
+        }
+        line.tokens.clear();
+    }
+    Ok(constants)
+}
+
+/// Lays the `.name ... .end` function blocks out after the global instructions, so they can be
+/// called as subroutines. The global instructions end in a HALT so they don't run into the
+/// functions, and each function starts with its name as a label and ends in a RET. Data goes
+/// after the functions, so it is never run as code.
+fn layout_functions(lines: Vec<Line>) -> Result<(Vec<Line>, Vec<String>), AsmError> {
+    let mut globals: Vec<Line> = Vec::new(); // Lines outside of any function
+    let mut functions = Vec::new(); // Lines of every function, in the order they were defined
+    let mut function_names = Vec::new();
+    let mut data = Vec::new(); // Data lines, which go after all of the code
+    let mut labels: Vec<Line> = Vec::new(); // Lines with just a label, which label the next line
+    let mut current_function: Option<(String, Line)> = None; // The function being defined, and its first line
+
+    for line in lines {
+        if line.tokens.is_empty() {
             continue; // Skip empty lines
         }
+        let rest = split_label(&line.tokens).1;
+        if rest.is_empty() {
+            labels.push(line);
+            continue;
+        }
+        if is_data(rest) {
+            data.append(&mut labels);
+            data.push(line);
+            continue;
+        }
+        match current_function {
+            Some(_) => functions.append(&mut labels),
+            None => globals.append(&mut labels),
+        }
 
         // Check if the first token indicates the start of a function
-        if tokens[0].starts_with('.') {
-            if tokens[0] == ".end" {
+        if let Some(directive) = directive(&line.tokens) {
+            if directive == ".end" {
                 // Handle the end of a function
-                if let Some(func_name) = current_function.take() {
-                    // Insert the function's instructions into the map
-                    functions.insert(func_name, current_function_instructions);
-                    current_function_instructions = Vec::new(); // Reset for the next function
+                if current_function.take().is_some() {
+                    functions.push(line.generated(vec![Token::generated(TokenKind::Word, "RET")]));
                 } else {
                     // Error if .end is found without a corresponding function
-                    println!(
-                        "Error: .end without a corresponding function on line {}.",
-                        line_number
-                    );
-                    std::process::exit(0);
+                    return Err(line.error(AsmErrorKind::EndWithoutFunction));
                 }
             } else {
                 // Start a new function
                 if current_function.is_none() {
-                    current_function = Some(tokens[0].to_string()); // Store the function name
+                    let name = directive[1..].to_string();
+                    functions.push(line.generated(vec![Token::generated(TokenKind::Label, &name)]));
+                    function_names.push(name.clone());
+                    current_function = Some((name, line)); // Store the function name
                 } else {
                     // Error if nested function definitions are found
-                    println!(
-                        "Error: Nested function definitions are not allowed on line {}.",
-                        line_number
-                    );
-                    std::process::exit(0);
+                    return Err(line.error(AsmErrorKind::NestedFunction));
                 }
             }
-        } else if let Some(ref _func_name) = current_function {
-            // Collect instructions for the current function
-            if let Some(instruction) = parse_instruction(tokens, line_number as i32) {
-                current_function_instructions.push(instruction); // Add instruction to the current function
+        } else if current_function.is_some() {
+            functions.push(line);
+        } else {
+            globals.push(line);
+        }
+    }
+
+    if let Some((name, start)) = current_function {
+        return Err(start.error(AsmErrorKind::MissingEnd(name)));
+    }
+    globals.append(&mut labels);
+
+    let halt = match globals.last() {
+        Some(last) => last.generated(vec![Token::generated(TokenKind::Word, "HALT")]),
+        None => Line {
+            number: 1,
+            file: String::new(),
+            text: String::new(),
+            main_line: 1,
+            tokens: vec![Token::generated(TokenKind::Word, "HALT")],
+            macros: Vec::new(),
+        },
+    };
+    globals.push(halt);
+    globals.extend(functions);
+    globals.extend(data);
+    Ok((globals, function_names))
+}
+
+/// First pass: records the address of every label.
+fn collect_labels(
+    lines: &[Line],
+    constants: &HashMap<String, i32>,
+) -> Result<HashMap<String, u16>, AsmError> {
+    let mut labels = HashMap::new(); // Map of label names to instruction addresses
+    let mut address = 0; // Address of the next instruction
+    let mut overflow = None; // The first line that doesn't fit in memory
+
+    for line in lines {
+        let (label, rest) = split_label(&line.tokens);
+        if let Some(label) = label {
+            let column = line.tokens[0].column;
+            if !is_valid_label(label) {
+                return Err(line.error_at(AsmErrorKind::InvalidLabel(label.to_string()), column));
+            }
+            let taken = constants.contains_key(label); // Labels and constants share their names
+            if labels.insert(label.to_string(), address as u16).is_some() || taken {
+                return Err(line.error_at(AsmErrorKind::DuplicateLabel(label.to_string()), column));
+            }
+        }
+        if is_data(rest) {
+            address += data_size(line, rest, constants, address)?;
+        } else if let Some(mnemonic) = rest.first() {
+            address += instruction_size(&mnemonic.text) as usize;
+        }
+        if address > MEMORY_SIZE && overflow.is_none() {
+            overflow = Some(line);
+        }
+    }
+
+    // Addresses past the end of memory don't fit in a u16, so the program can't go further
+    if let Some(line) = overflow {
+        return Err(line.error(AsmErrorKind::ProgramTooLarge(address)));
+    }
+    Ok(labels)
+}
+
+/// An assembled program, along with what's needed to map it back to its source.
+#[derive(Debug)]
+pub struct Program {
+    pub instructions: Vec<Instruction>, // Followed by the data, as WORDs
+    pub source_map: Vec<(u16, usize)>,  // Address and source line of each instruction
+    pub labels: HashMap<String, u16>,
+    pub exports: Vec<String>, // Labels named by `.global`
+    pub imports: Vec<String>, // Symbols named by `.extern`, defined in another file
+    // Address and kind of each word that holds the address of a label
+    pub relocations: Vec<(u16, RelocationKind, String)>,
+}
+
+impl Program {
+    /// Finds the source line of the instruction at an address.
+    pub fn line_at(&self, address: u16) -> Option<usize> {
+        self.source_map
+            .iter()
+            .find(|(instruction_address, _)| *instruction_address == address)
+            .map(|(_, line)| *line)
+    }
+
+    /// Finds the address of the first instruction on or after a source line.
+    pub fn address_of_line(&self, line: usize) -> Option<u16> {
+        self.source_map
+            .iter()
+            .filter(|(_, instruction_line)| *instruction_line >= line)
+            .min_by_key(|(_, instruction_line)| *instruction_line)
+            .map(|(address, _)| *address)
+    }
+}
+
+/// Parses the tokenized lines into instructions, handling functions internally.
+/// The path of the file is used to find the files it includes.
+pub fn parse_file(path: &str, f_contents: String) -> Result<Vec<Instruction>, AsmError> {
+    parse_program(path, f_contents).map(|program| program.instructions)
+}
+
+/// Like `parse_file`, but keeps the source map and labels.
+pub fn parse_program(path: &str, f_contents: String) -> Result<Program, AsmError> {
+    parse_lines(path, &f_contents, false)
+}
+
+/// Parses one file of a program that gets linked, which may use symbols from other files.
+pub fn parse_module(path: &str, f_contents: String) -> Result<Program, AsmError> {
+    parse_lines(path, &f_contents, true)
+}
+
+fn parse_lines(path: &str, f_contents: &str, linking: bool) -> Result<Program, AsmError> {
+    let mut instructions = Vec::new(); // Vector to store parsed instructions
+    let mut source_map = Vec::new();
+    let mut relocations = Vec::new();
+    let mut address = 0;
+    let config = declare_config(); // Obtain configuration settings
+    let tokens = lex(f_contents, "")?; // Tokenize the input contents
+
+    // The file being assembled counts as included, so including it again is a cycle
+    let path = Path::new(path);
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut includes = Includes {
+        paths: config.include_paths.iter().map(PathBuf::from).collect(),
+        stack: vec![canonical.clone()],
+        included: HashSet::from([canonical]),
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let tokens = include_files(tokens, dir, &mut includes)?;
+    let mut tokens = expand_macros(tokens)?;
+    let symbols = collect_symbols(&mut tokens)?;
+    let constants = collect_constants(&mut tokens)?;
+
+    if config.verbose_debug {
+        // If verbose debugging is enabled, print the tokenized instructions
+        println!("Tokenized instructions:\n{:?}", tokens);
+    }
+
+    let (lines, functions) = layout_functions(tokens)?; // Move functions after the global code
+    let mut labels = collect_labels(&lines, &constants)?; // Resolve label addresses before parsing
+
+    for (name, line) in &symbols.exports {
+        if !labels.contains_key(name) {
+            return Err(line.error(AsmErrorKind::UnknownLabel(name.clone())));
+        }
+    }
+    let imports: Vec<String> = symbols
+        .imports
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect();
+    for (name, line) in &symbols.imports {
+        if !linking {
+            return Err(line.error(AsmErrorKind::UnlinkedSymbol(name.clone())));
+        }
+        // Imported symbols are at 0 until the linker knows where they are
+        if labels.insert(name.clone(), 0).is_some() || constants.contains_key(name) {
+            return Err(line.error(AsmErrorKind::DuplicateLabel(name.clone())));
+        }
+    }
+
+    let scope = Scope {
+        constants: &constants,
+        labels: &labels,
+        imports: &imports,
+    };
+
+    // Iterate over the laid out lines
+    for line in &lines {
+        let tokens = split_label(&line.tokens).1;
+        if is_data(tokens) {
+            let words = parse_data(line, tokens, &scope, address)?;
+            for (word, label) in words {
+                if let Some(label) = label {
+                    relocations.push((address as u16, RelocationKind::Word, label));
+                }
+                instructions.push(Instruction::WORD(word));
+                address += 1;
             }
-        } else if let Some(instruction) = parse_instruction(tokens, line_number as i32) {
-            // Add instruction to the global instructions
+            continue;
+        }
+        if let Some((instruction, relocation)) = parse_instruction(line, &scope)? {
             instructions.push(instruction);
+            source_map.push((address as u16, line.main_line));
+            if let Some((offset, kind, label)) = relocation {
+                relocations.push((address as u16 + offset, kind, label));
+            }
+            address += instruction_size(&tokens[0].text) as usize;
         }
     }
 
     if config.verbose_debug {
-        // Print global instructions and functions if verbose debugging is enabled
-        println!("Global instructions: {:?}", instructions);
+        // Print instructions and functions if verbose debugging is enabled
+        println!("Instructions: {:?}", instructions);
         println!("Functions: {:?}", functions);
+        println!("Labels: {:?}", labels);
+        println!("Constants: {:?}", constants);
     }
 
-    // Ensure HALT instruction is at the end of global instructions
-    //instructions.push(Instruction::HALT);
+    for name in &imports {
+        labels.remove(name);
+    }
+    Ok(Program {
+        instructions,
+        source_map,
+        labels,
+        exports: symbols.exports.into_iter().map(|(name, _)| name).collect(),
+        imports,
+        relocations,
+    })
+}
 
-    instructions // Return the collected instructions
+/// What an operand of an instruction has to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperandKind {
+    Register,            // A register name, or its number from 0 to 15
+    Immediate(i32, i32), // A number from the minimum to the maximum
+    RegisterOrImmediate, // The source of a MOV, which is a register or an 8 bit number
+    Address,             // A jump or call target inside memory
+    Memory,              // [address], [reg] or [reg+offset]
 }
 
-/// Parses a single instruction from tokens.
-fn parse_instruction(tokens: &[String], line_number: i32) -> Option<Instruction> {
-    if tokens.is_empty() {
-        return None; // Return None if no instruction is found
+/// The operands each instruction takes, in order, or None for an unknown instruction.
+fn operand_schema(mnemonic: &str) -> Option<&'static [OperandKind]> {
+    use OperandKind::*;
+    let schema: &[OperandKind] = match mnemonic {
+        "ADD" | "SUB" | "MUL" | "SWAP" | "DIV" | "CMP" | "MOVR" => &[Register, Register],
+        "IMUL" | "IDIV" | "ICMP" | "AND" | "OR" | "XOR" => &[Register, Register],
+        "MOV" => &[Register, RegisterOrImmediate],
+        "POW" => &[Register, Immediate(0, 255)],
+        "SHL" | "SHR" | "ROL" | "ROR" => &[Register, Immediate(0, 15)],
+        "CLR" | "INC" | "DEC" | "PRINT" | "NOT" | "IN" | "PUSH" | "POP" => &[Register],
+        "JMP" | "JZ" | "JE" | "JNZ" | "JNE" | "JC" | "JB" | "JNC" | "JAE" | "JN" | "JS" => {
+            &[Address]
+        }
+        "JNN" | "JNS" | "JO" | "JNO" | "JA" | "JBE" | "JL" | "JGE" | "JG" | "JLE" | "CALL" => {
+            &[Address]
+        }
+        "LOAD" => &[Register, Memory],
+        "STORE" => &[Memory, Register],
+        "HALT" | "RET" | "NOP" => &[],
+        _ => return None,
+    };
+    Some(schema)
+}
+
+/// A parsed operand.
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u16),
+    Number(u16), // An immediate or an address
+    Memory(MemoryOperand),
+}
+
+/// Where in an instruction a label's address goes, as an offset from its first word.
+type InstructionRelocation = (u16, RelocationKind, String);
+
+/// Parses the instruction on a line, checking that it has the operands its schema says. If an
+/// operand uses a label, the instruction comes with the relocation that moves it.
+fn parse_instruction(
+    line: &Line,
+    scope: &Scope,
+) -> Result<Option<(Instruction, Option<InstructionRelocation>)>, AsmError> {
+    let Some((mnemonic, rest)) = split_label(&line.tokens).1.split_first() else {
+        return Ok(None); // Return None if no instruction is found
+    };
+    let unknown = || {
+        line.error_at(
+            AsmErrorKind::UnknownInstruction(mnemonic.text.clone()),
+            mnemonic.column,
+        )
+    };
+    let instruc = mnemonic.text.to_uppercase(); // Get the instruction name
+    let Some(schema) = operand_schema(&instruc) else {
+        return Err(unknown());
+    };
+    let args = operands(line, after_name(rest))?;
+    if args.len() != schema.len() {
+        // Point at the first operand too many, if there are too many
+        let column = args
+            .get(schema.len())
+            .map_or(mnemonic.column, |extra| extra[0].column);
+        return Err(line.error_at(
+            AsmErrorKind::WrongOperandCount(
+                mnemonic.text.clone(),
+                schema.len() as u16,
+                args.len() as u16,
+            ),
+            column,
+        ));
     }
-    let instruc = &tokens[0]; // Get the instruction name
-    let (dest, src): (u16, u16) = parse_operands(tokens); // Parse destination and source operands
+    let mut label = None; // Only one operand of an instruction can hold a number
+    let operands = args
+        .iter()
+        .zip(schema)
+        .map(|(operand, kind)| {
+            let (operand_value, operand_label) = parse_operand(operand, *kind, scope)
+                .map_err(|error| line.error_at(error, operand[0].column))?;
+            label = label.take().or(operand_label);
+            Ok(operand_value)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
+    use Operand::{Memory, Number, Register as Reg};
     // Match the instruction name and create the appropriate Instruction variant
-    match instruc.to_uppercase().as_str() {
-        "ADD" => Some(Instruction::ADD(dest, src)),
-        "SUB" => Some(Instruction::SUB(dest, src)),
-        "MUL" => Some(Instruction::MUL(dest, src)),
-        "MOV" => {
-            // Function to create a MOV instruction based on the destination and source
-            fn create_mov_instruction(dest: u16, src: Option<&str>) -> Instruction {
-                match src {
-                    Some(value) => {
-                        // Try to parse the source value as u16
-                        if let Ok(parsed_value) = value.parse::<u16>() {
-                            Instruction::MOV(dest, parsed_value) // Move immediate value
-                        } else {
-                            // If parsing fails, treat the value as a register
-                            let reg_index =
-                                letter_to_integer(value.chars().next().unwrap_or(' ')).unwrap_or(0);
-                            Instruction::MOVR(dest, reg_index.into()) // Move from register
-                        }
-                    }
-                    None => Instruction::MOV(dest, 0), // Default to moving 0 if src is None
-                }
+    let instruction = match (instruc.as_str(), operands.as_slice()) {
+        ("ADD", &[Reg(dest), Reg(src)]) => Instruction::ADD(dest, src),
+        ("SUB", &[Reg(dest), Reg(src)]) => Instruction::SUB(dest, src),
+        ("MUL", &[Reg(dest), Reg(src)]) => Instruction::MUL(dest, src),
+        ("MOV", &[Reg(dest), Reg(src)]) => Instruction::MOVR(dest, src), // Move from register
+        // Negative values are stored as an 8 bit two's-complement immediate
+        ("MOV", &[Reg(dest), Number(value)]) => Instruction::MOV(dest, value & 0xFF),
+        ("SWAP", &[Reg(dest), Reg(src)]) => Instruction::SWAP(dest, src),
+        ("DIV", &[Reg(dest), Reg(src)]) => Instruction::DIV(dest, src),
+        ("CLR", &[Reg(reg)]) => Instruction::CLR(reg),
+        ("DEC", &[Reg(reg)]) => Instruction::DEC(reg),
+        ("INC", &[Reg(reg)]) => Instruction::INC(reg),
+        ("CMP", &[Reg(dest), Reg(src)]) => Instruction::CMP(dest, src),
+        ("HALT", []) => Instruction::HALT,
+        ("PRINT", &[Reg(reg)]) => Instruction::PRINT(reg),
+        ("POW", &[Reg(dest), Number(power)]) => Instruction::POW(dest, power),
+        ("MOVR", &[Reg(dest), Reg(src)]) => Instruction::MOVR(dest, src),
+        ("JMP", &[Number(target)]) => Instruction::JMP(target),
+        ("JZ" | "JE", &[Number(target)]) => Instruction::JZ(target),
+        ("JNZ" | "JNE", &[Number(target)]) => Instruction::JNZ(target),
+        ("JC" | "JB", &[Number(target)]) => Instruction::JC(target),
+        ("JNC" | "JAE", &[Number(target)]) => Instruction::JNC(target),
+        ("JN" | "JS", &[Number(target)]) => Instruction::JN(target),
+        ("JNN" | "JNS", &[Number(target)]) => Instruction::JNN(target),
+        ("JO", &[Number(target)]) => Instruction::JO(target),
+        ("JNO", &[Number(target)]) => Instruction::JNO(target),
+        ("JA", &[Number(target)]) => Instruction::JA(target),
+        ("JBE", &[Number(target)]) => Instruction::JBE(target),
+        ("JL", &[Number(target)]) => Instruction::JL(target),
+        ("JGE", &[Number(target)]) => Instruction::JGE(target),
+        ("JG", &[Number(target)]) => Instruction::JG(target),
+        ("JLE", &[Number(target)]) => Instruction::JLE(target),
+        ("LOAD", &[Reg(dest), Memory(MemoryOperand::Direct(address))]) => {
+            Instruction::LOAD(dest, address)
+        }
+        ("LOAD", &[Reg(dest), Memory(MemoryOperand::Indexed(base, offset))]) => {
+            Instruction::LOADX(dest, base, offset)
+        }
+        ("STORE", &[Memory(MemoryOperand::Direct(address)), Reg(src)]) => {
+            Instruction::STORE(address, src)
+        }
+        ("STORE", &[Memory(MemoryOperand::Indexed(base, offset)), Reg(src)]) => {
+            Instruction::STOREX(base, offset, src)
+        }
+        ("IMUL", &[Reg(dest), Reg(src)]) => Instruction::IMUL(dest, src),
+        ("IDIV", &[Reg(dest), Reg(src)]) => Instruction::IDIV(dest, src),
+        ("ICMP", &[Reg(dest), Reg(src)]) => Instruction::ICMP(dest, src),
+        ("AND", &[Reg(dest), Reg(src)]) => Instruction::AND(dest, src),
+        ("OR", &[Reg(dest), Reg(src)]) => Instruction::OR(dest, src),
+        ("XOR", &[Reg(dest), Reg(src)]) => Instruction::XOR(dest, src),
+        ("NOT", &[Reg(reg)]) => Instruction::NOT(reg),
+        ("SHL", &[Reg(reg), Number(count)]) => Instruction::SHL(reg, count),
+        ("SHR", &[Reg(reg), Number(count)]) => Instruction::SHR(reg, count),
+        ("ROL", &[Reg(reg), Number(count)]) => Instruction::ROL(reg, count),
+        ("ROR", &[Reg(reg), Number(count)]) => Instruction::ROR(reg, count),
+        ("IN", &[Reg(reg)]) => Instruction::IN(reg),
+        ("PUSH", &[Reg(reg)]) => Instruction::PUSH(reg),
+        ("POP", &[Reg(reg)]) => Instruction::POP(reg),
+        ("CALL", &[Number(target)]) => Instruction::CALL(target),
+        ("RET", []) => Instruction::RET,
+        ("NOP", []) => Instruction::NOP,
+        _ => {
+            // Every schema is matched above, so this means the two have gone out of sync
+            return Err(unknown());
+        }
+    };
+    // Two-word instructions keep the number in their second word, and only the direct
+    // addresses there take the whole word. Everything else is in the low 8 bits.
+    let relocation = label.map(|label| {
+        let kind = match instruction {
+            Instruction::LOAD(..) | Instruction::STORE(..) | Instruction::CALL(_) => {
+                RelocationKind::Word
             }
+            _ => RelocationKind::Low8,
+        };
+        (instruction_size(&instruc) - 1, kind, label)
+    });
+    Ok(Some((instruction, relocation)))
+}
 
-            // Convert dest and src to u16 and call create_mov_instruction
-            let instruction = create_mov_instruction(dest, Some(&tokens[2]));
-            Some(instruction)
-        }
-        "SWAP" => Some(Instruction::SWAP(dest, src)),
-        "DIV" => Some(Instruction::DIV(dest, src)),
-        "CLR" => Some(Instruction::CLR(dest)),
-        "DEC" => Some(Instruction::DEC(dest)),
-        "INC" => Some(Instruction::INC(dest)),
-        "CMP" => Some(Instruction::CMP(dest, src)),
-        "HALT" => Some(Instruction::HALT),
-        "PRINT" => Some(Instruction::PRINT(dest)),
-        "POW" => Some(Instruction::POW(dest, src)),
-        "MOVR" => Some(Instruction::MOVR(dest, src)),
-        "JMP" => Some(Instruction::JMP(dest)),
-        "NOP" => Some(Instruction::NOP),
-        _ => {
-            // Handle unknown instructions
-            println!(
-                "Error: Unknown instruction: \"{}\" on line {}.",
-                instruc, line_number
-            );
-            std::process::exit(0); // Exit if an unknown instruction is encountered
-        }
-    }
-}
-
-/// Parses the operands from the tokenized line.
-fn parse_operands(tokens: &[String]) -> (u16, u16) {
-    // Parse the first and second operands from the tokens, defaulting to 0 if not found
-    let dest = parse_value(tokens.get(1).unwrap_or(&"0".to_string()));
-    let src = parse_value(tokens.get(2).unwrap_or(&"0".to_string()));
-    (dest, src) // Return the parsed operands as a tuple
-}
-
-/// Converts a token into a u16 value, handling both numeric and register inputs.
-fn parse_value(token: &String) -> u16 {
-    // Check if the token is a binary number
-    if token.starts_with("b") && has_b_with_num(token) {
-        i32::from_str_radix(&token[2..], 2).unwrap_or_else(|_| {
-            // Handle invalid binary numbers
-            println!("Error: Not a valid binary number: {}", token);
-            std::process::exit(0);
-        }) as u16 // Cast to u16
-    } else if let Ok(value) = token.parse::<u16>() {
-        // Attempt to parse the token as a u16
-        value
+/// Parses an operand as the kind of operand its instruction expects there.
+/// Also returns the label the operand's value is relative to, if any.
+fn parse_operand(
+    tokens: &[Token],
+    kind: OperandKind,
+    scope: &Scope,
+) -> Result<(Operand, Option<String>), AsmErrorKind> {
+    let token = join(tokens);
+    let token = token.as_str();
+    if kind != OperandKind::Memory && tokens[0].kind == TokenKind::OpenBracket {
+        return Err(AsmErrorKind::UnexpectedMemoryOperand(token.to_string()));
+    }
+    // A register where memory goes is a missing memory operand, caught below
+    let takes_number = !matches!(
+        kind,
+        OperandKind::Register | OperandKind::RegisterOrImmediate | OperandKind::Memory
+    );
+    // Labels can have the same name as a register, like a function called `f`
+    if takes_number && register_index(token).is_some() && !scope.labels.contains_key(token) {
+        return Err(AsmErrorKind::ExpectedNumber(token.to_string()));
+    }
+    let number = |(value, label)| (Operand::Number(value), label);
+    match kind {
+        OperandKind::Register => {
+            parse_register(token, scope).map(|register| (Operand::Register(register), None))
+        }
+        OperandKind::Immediate(min, max) => parse_immediate(token, (min, max), scope).map(number),
+        OperandKind::RegisterOrImmediate => match register_index(token) {
+            Some(register) => Ok((Operand::Register(register), None)),
+            None => parse_immediate(token, (-128, 255), scope).map(number),
+        },
+        OperandKind::Address => parse_address(token, scope).map(number),
+        OperandKind::Memory => parse_memory_operand(tokens, scope)
+            .map(|(operand, label)| (Operand::Memory(operand), label)),
+    }
+}
+
+/// The index of a register name, from `a` or `ax` to `p` or `px`.
+fn register_index(token: &str) -> Option<u16> {
+    let name = token.to_ascii_lowercase();
+    let mut chars = name.chars();
+    let letter = chars.next()?;
+    if ('a'..='p').contains(&letter) && matches!(chars.as_str(), "" | "x") {
+        letter_to_integer(letter).map(u16::from)
     } else {
-        // If parsing fails, treat the token as a register
-        letter_to_integer(token.chars().next().unwrap_or(' '))
-            .unwrap_or(0)
-            .into() // Convert to u16
+        None
+    }
+}
+
+/// Parses a register operand, which is a register name or its number from 0 to 15.
+fn parse_register(token: &str, scope: &Scope) -> Result<u16, AsmErrorKind> {
+    if let Some(register) = register_index(token) {
+        return Ok(register);
+    }
+    match evaluate(token, scope) {
+        Ok(ExprValue {
+            value: register @ 0..=15,
+            label: None,
+        }) => Ok(register as u16),
+        Ok(ExprValue { label: None, .. }) => {
+            Err(AsmErrorKind::ValueOutOfRange(token.to_string(), 0, 15))
+        }
+        _ => Err(AsmErrorKind::ExpectedRegister(token.to_string())),
+    }
+}
+
+/// Parses a number operand that has to fit in its field of the instruction, like the shift
+/// count of a shift or rotate.
+fn parse_immediate(
+    token: &str,
+    (min, max): (i32, i32),
+    scope: &Scope,
+) -> Result<(u16, Option<String>), AsmErrorKind> {
+    match evaluate(token, scope)? {
+        // A label only fits where any address would, so it can still be moved when linking
+        ExprValue { label: Some(_), .. } if max < MEMORY_SIZE as i32 - 1 => {
+            Err(AsmErrorKind::LabelInImmediate(token.to_string(), min, max))
+        }
+        ExprValue { value, label } if (min..=max).contains(&value) => Ok((value as u16, label)),
+        _ => Err(AsmErrorKind::ValueOutOfRange(token.to_string(), min, max)),
+    }
+}
+
+/// Resolves a jump target, which is an expression using labels or numbers.
+fn parse_address(token: &str, scope: &Scope) -> Result<(u16, Option<String>), AsmErrorKind> {
+    let ExprValue { value, label } = evaluate(token, scope)?;
+    if !(0..MEMORY_SIZE as i32).contains(&value) {
+        return Err(AsmErrorKind::AddressOutOfRange(value));
+    }
+    Ok((value as u16, label))
+}
+
+/// A memory operand, written as `[address]`, `[reg]` or `[reg+offset]`.
+#[derive(Debug, Clone, Copy)]
+enum MemoryOperand {
+    Direct(u16),
+    Indexed(u16, u16), // Base register, offset
+}
+
+/// Strips the brackets off a memory operand.
+fn memory_operand_inner(tokens: &[Token]) -> Option<&[Token]> {
+    match tokens {
+        [open, inner @ .., close]
+            if open.kind == TokenKind::OpenBracket && close.kind == TokenKind::CloseBracket =>
+        {
+            Some(inner)
+        }
+        _ => None,
+    }
+}
+
+/// Parses a memory operand. Anything that isn't a register, or a register plus an offset, is
+/// an expression for a direct address.
+fn parse_memory_operand(
+    tokens: &[Token],
+    scope: &Scope,
+) -> Result<(MemoryOperand, Option<String>), AsmErrorKind> {
+    let Some(inner) = memory_operand_inner(tokens) else {
+        return Err(AsmErrorKind::InvalidMemoryOperand);
+    };
+
+    match inner {
+        [base] if register_index(&base.text).is_some() => Ok((
+            MemoryOperand::Indexed(register_index(&base.text).unwrap_or(0), 0),
+            None,
+        )),
+        [base, plus, offset @ ..] if register_index(&base.text).is_some() && plus.text == "+" => {
+            let offset = join(offset);
+            match evaluate(&offset, scope)? {
+                ExprValue {
+                    value: value @ 0..=255,
+                    label,
+                } => Ok((
+                    MemoryOperand::Indexed(register_index(&base.text).unwrap_or(0), value as u16),
+                    label,
+                )),
+                _ => Err(AsmErrorKind::ValueOutOfRange(offset, 0, 255)),
+            }
+        }
+        _ => {
+            let (address, label) = parse_address(&join(inner), scope)?;
+            Ok((MemoryOperand::Direct(address), label))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<(TokenKind, String, usize)> {
+        tokenize_line(text)
+            .expect("line tokenizes")
+            .into_iter()
+            .map(|token| (token.kind, token.text, token.column))
+            .collect()
+    }
+
+    fn token(kind: TokenKind, text: &str, column: usize) -> (TokenKind, String, usize) {
+        (kind, text.to_string(), column)
+    }
+
+    #[test]
+    fn tokenizes_with_columns() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens("loop: load dx, [bx + tbl-1]"),
+            [
+                token(Label, "loop", 1),
+                token(Word, "load", 7),
+                token(Word, "dx", 12),
+                token(Comma, ",", 14),
+                token(OpenBracket, "[", 16),
+                token(Word, "bx", 17),
+                token(Operator, "+", 20),
+                token(Word, "tbl", 22),
+                token(Operator, "-", 25),
+                token(Word, "1", 26),
+                token(CloseBracket, "]", 27),
+            ]
+        );
+        assert_eq!(
+            tokens("\t.equ MAX (1<<4)|0x0F"),
+            [
+                token(Directive, ".equ", 2),
+                token(Word, "MAX", 7),
+                token(Operator, "(", 11),
+                token(Word, "1", 12),
+                token(Operator, "<<", 13),
+                token(Word, "4", 15),
+                token(Operator, ")", 16),
+                token(Operator, "|", 17),
+                token(Word, "0x0F", 18),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_quotes_together() {
+        use TokenKind::*;
+        assert_eq!(
+            tokens(r#"msg: .string "a; b # \"c\"", ';'"#),
+            [
+                token(Label, "msg", 1),
+                token(Directive, ".string", 6),
+                token(String, r#""a; b # \"c\"""#, 14),
+                token(Comma, ",", 28),
+                token(Char, "';'", 30),
+            ]
+        );
+        assert_eq!(
+            tokenize_line(r#".string "open"#),
+            Err((AsmErrorKind::InvalidString("\"open".to_string()), 9))
+        );
+        assert_eq!(
+            tokenize_line("mov ax, 'a"),
+            Err((AsmErrorKind::InvalidCharacter("'a".to_string()), 9))
+        );
+    }
+
+    #[test]
+    fn skips_comments() {
+        assert_eq!(tokens("  ; a comment"), []);
+        assert_eq!(tokens("# another"), []);
+        assert_eq!(
+            tokens("halt ; stop [here]"),
+            [token(TokenKind::Word, "halt", 1)]
+        );
+        assert_eq!(
+            tokens("inc ax# no space"),
+            [
+                token(TokenKind::Word, "inc", 1),
+                token(TokenKind::Word, "ax", 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unexpected_characters() {
+        assert_eq!(
+            tokenize_line("mov ax, $5"),
+            Err((AsmErrorKind::UnexpectedCharacter('$'), 9))
+        );
+        assert_eq!(
+            tokenize_line("a < b"),
+            Err((AsmErrorKind::UnexpectedCharacter('<'), 3))
+        );
+        assert_eq!(
+            AsmErrorKind::UnexpectedCharacter('\u{1}').to_string(),
+            "unexpected character '\\u{1}'"
+        );
+    }
+
+    #[test]
+    fn fills_memory_exactly() {
+        // The HALT at the end of the code takes the last word
+        let source = format!(".zero {}\n", MEMORY_SIZE - 1);
+        let program = parse_program("fits.asm", source).expect("program fits");
+        assert_eq!(program.instructions.len(), MEMORY_SIZE);
+    }
+
+    #[test]
+    fn rejects_programs_larger_than_memory() {
+        let source = ".zero 255\n".repeat(300);
+        let error = parse_program("big.asm", source).expect_err("program is too large");
+        assert_eq!(error.kind, AsmErrorKind::ProgramTooLarge(300 * 255 + 1));
+        assert_eq!(error.line, 1);
+    }
+
+    const COUNTDOWN: &str = ".macro countdown reg, from
+mov reg, from
+again:
+dec reg
+jnz again
+.endm
+";
+
+    #[test]
+    fn gives_each_expansion_its_own_labels() {
+        let source = format!("{}countdown ax, 3\ncountdown bx, 5\n", COUNTDOWN);
+        let program = parse_program("countdown.asm", source).expect("program assembles");
+        assert_eq!(
+            program.instructions[..6],
+            [
+                Instruction::MOV(0, 3),
+                Instruction::DEC(0),
+                Instruction::JNZ(1),
+                Instruction::MOV(1, 5),
+                Instruction::DEC(1),
+                Instruction::JNZ(4),
+            ]
+        );
+        assert_eq!(program.labels["__countdown_1_again"], 1);
+        assert_eq!(program.labels["__countdown_2_again"], 4);
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_macro_arguments() {
+        let source = format!("{}countdown ax\n", COUNTDOWN);
+        let error = parse_program("countdown.asm", source).expect_err("too few arguments");
+        assert_eq!(
+            error.kind,
+            AsmErrorKind::WrongArgumentCount("countdown".to_string(), 2, 1)
+        );
+        assert_eq!(error.line, 7);
+
+        let source = format!("{}countdown ax, 3, 4\n", COUNTDOWN);
+        let error = parse_program("countdown.asm", source).expect_err("too many arguments");
+        assert_eq!(
+            error.kind,
+            AsmErrorKind::WrongArgumentCount("countdown".to_string(), 2, 3)
+        );
+    }
+
+    #[test]
+    fn rejects_recursive_macros() {
+        let source = ".macro forever\ninc ax\nforever\n.endm\nforever\n";
+        let error = parse_program("forever.asm", source.to_string()).expect_err("recursive");
+        assert_eq!(
+            error.kind,
+            AsmErrorKind::RecursiveMacro("forever".to_string())
+        );
+        assert_eq!(error.line, 5);
+        assert_eq!(error.macros.len(), 1);
+        assert_eq!(error.macros[0].line, 3);
+
+        // Macros that use each other are caught the same way
+        let source = ".macro ping\npong\n.endm\n.macro pong\nping\n.endm\nping\n";
+        let error = parse_program("ping.asm", source.to_string()).expect_err("recursive");
+        assert!(matches!(error.kind, AsmErrorKind::RecursiveMacro(_)));
+    }
+
+    /// Writes files into a fresh directory for the include tests, and returns the directory.
+    fn include_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("alice-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("create include test directory");
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).expect("write include test file");
+        }
+        dir
+    }
+
+    #[test]
+    fn includes_each_file_once() {
+        let dir = include_dir("once", &[("counter.asm", "counter: inc ax\n")]);
+        let main = dir.join("main.asm");
+        let main = main.to_str().expect("path");
+
+        let once = parse_program(main, ".include \"counter.asm\"\n".to_string()).expect("once");
+        let twice = ".include \"counter.asm\"\n.include \"counter.asm\"\n";
+        let twice = parse_program(main, twice.to_string()).expect("twice");
+        assert_eq!(twice.instructions, once.instructions);
+        assert!(twice.labels.contains_key("counter"));
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rejects_include_cycles() {
+        let dir = include_dir(
+            "cycle",
+            &[
+                ("first.asm", ".include \"second.asm\"\n"),
+                ("second.asm", "inc ax\n.include \"first.asm\"\n"),
+            ],
+        );
+        let main = dir.join("main.asm");
+        let error = parse_program(
+            main.to_str().expect("path"),
+            ".include \"first.asm\"\n".into(),
+        )
+        .expect_err("cycle");
+        assert_eq!(
+            error.kind,
+            AsmErrorKind::IncludeCycle("first.asm".to_string())
+        );
+        assert_eq!(error.line, 2);
+        assert!(error.file.ends_with("second.asm"));
+
+        // Including the file being assembled is a cycle too
+        fs::write(&main, "inc ax\n.include \"main.asm\"\n").expect("write main.asm");
+        let error = parse_program(
+            main.to_str().expect("path"),
+            ".include \"main.asm\"\n".into(),
+        )
+        .expect_err("cycle");
+        assert_eq!(
+            error.kind,
+            AsmErrorKind::IncludeCycle("main.asm".to_string())
+        );
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn reports_errors_in_the_included_file() {
+        let dir = include_dir("error", &[("broken.asm", "inc ax\n\n  frob bx\n")]);
+        let main = dir.join("main.asm");
+        let source = "dec ax\n.include \"broken.asm\"\n";
+        let error =
+            parse_program(main.to_str().expect("path"), source.into()).expect_err("unknown");
+        assert!(matches!(error.kind, AsmErrorKind::UnknownInstruction(_)));
+        assert!(error.file.ends_with("broken.asm"));
+        assert_eq!(error.line, 3);
+        assert_eq!(error.text, "  frob bx");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    /// Assembles a one-line program that should fail, and returns the error and its column.
+    fn operand_error(source: &str) -> (AsmErrorKind, usize) {
+        let error = parse_program("operands.asm", source.to_string()).expect_err(source);
+        assert_eq!(error.line, 1, "{source}");
+        (error.kind, error.column)
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_operands() {
+        let count = |mnemonic: &str, expected, given| {
+            AsmErrorKind::WrongOperandCount(mnemonic.to_string(), expected, given)
+        };
+        // Too few operands are reported at the mnemonic
+        assert_eq!(operand_error("add ax"), (count("add", 2, 1), 1));
+        assert_eq!(operand_error("  PUSH"), (count("PUSH", 1, 0), 3));
+        assert_eq!(operand_error("start: store bx"), (count("store", 2, 1), 8));
+        // Too many at the first operand too many
+        assert_eq!(operand_error("inc ax, bx"), (count("inc", 1, 2), 9));
+        assert_eq!(operand_error("halt ax"), (count("halt", 0, 1), 6));
+        assert_eq!(operand_error("mov ax, 1, 2"), (count("mov", 2, 3), 12));
+    }
+
+    #[test]
+    fn rejects_the_wrong_kind_of_operand() {
+        let text = |kind: fn(String) -> AsmErrorKind, token: &str| kind(token.to_string());
+        // A number where a register goes
+        assert_eq!(
+            operand_error("add ax, 300"),
+            (AsmErrorKind::ValueOutOfRange("300".to_string(), 0, 15), 9)
+        );
+        assert_eq!(
+            operand_error("push later\nlater: halt"),
+            (text(AsmErrorKind::ExpectedRegister, "later"), 6)
+        );
+        // A register where a number or address goes
+        assert_eq!(
+            operand_error("shl ax, bx"),
+            (text(AsmErrorKind::ExpectedNumber, "bx"), 9)
+        );
+        assert_eq!(
+            operand_error("jmp cx"),
+            (text(AsmErrorKind::ExpectedNumber, "cx"), 5)
+        );
+        // Memory where no memory operand goes
+        assert_eq!(
+            operand_error("mov ax, [bx]"),
+            (text(AsmErrorKind::UnexpectedMemoryOperand, "[bx]"), 9)
+        );
+        assert_eq!(
+            operand_error("store [ax], [bx]"),
+            (text(AsmErrorKind::UnexpectedMemoryOperand, "[bx]"), 13)
+        );
+        // No memory where a memory operand goes
+        assert_eq!(
+            operand_error("load ax, bx"),
+            (AsmErrorKind::InvalidMemoryOperand, 10)
+        );
+        assert_eq!(
+            operand_error("store 5, ax"),
+            (AsmErrorKind::InvalidMemoryOperand, 7)
+        );
     }
 }